@@ -0,0 +1,806 @@
+/*!
+ *  ACPI table discovery and parsing.
+ *
+ *  The Root System Description Pointer (RSDP) is searched for in the
+ *  BIOS areas, leading to the RSDT (or, from ACPI 2.0 on, the XSDT),
+ *  whose entries point to every other table. Each table starts with
+ *  the same header, holding its signature, length and checksum.
+ *
+ *  The following tables are parsed into typed structures:
+ *
+ *  | Signature | Table                                               |
+ *  |-----------|-----------------------------------------------------|
+ *  | `APIC`    | MADT, interrupt controllers and ISA IRQ overrides   |
+ *  | `FACP`    | FADT, power management blocks and reset register    |
+ *  | `HPET`    | HPET, event timer block                             |
+ *  | `MCFG`    | PCI Express memory mapped configuration spaces      |
+ *
+ *  Tables are read through the complete physical memory mapping,
+ *  so `memory::init` must have been called before `init`.
+ */
+
+use core::{fmt, str};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    PhysAddr, VirtAddr
+};
+
+use crate::memory;
+use crate::println;
+
+/// Maximum number of tables kept from RSDT/XSDT.
+pub const MAX_TABLES: usize = 32;
+
+/// Maximum number of processor local APICs kept from MADT.
+pub const MAX_LOCAL_APICS: usize = 16;
+
+/// Maximum number of I/O APICs kept from MADT.
+pub const MAX_IO_APICS: usize = 4;
+
+/// Maximum number of interrupt source overrides kept from MADT.
+pub const MAX_OVERRIDES: usize = 16;
+
+/// Maximum number of configuration spaces kept from MCFG.
+pub const MAX_PCI_SEGMENTS: usize = 4;
+
+/**
+ *  Locates the RSDP and parses every known table.
+ */
+pub fn init() -> Result<(), AcpiError> {
+    let acpi = parse()?;
+    without_interrupts(|| *ACPI.lock() = Some(acpi));
+    Ok(())
+}
+
+/**
+ *  Gets everything found on `init`, if it succeeded.
+ */
+pub fn tables() -> Option<Acpi> {
+    without_interrupts(|| *ACPI.lock())
+}
+
+/**
+ *  Gets the MADT, if found.
+ */
+pub fn madt() -> Option<Madt> {
+    tables()?.madt
+}
+
+/**
+ *  Gets the FADT, if found.
+ */
+pub fn fadt() -> Option<Fadt> {
+    tables()?.fadt
+}
+
+/**
+ *  Gets the HPET table, if found.
+ */
+pub fn hpet() -> Option<Hpet> {
+    tables()?.hpet
+}
+
+/**
+ *  Gets the MCFG table, if found.
+ */
+pub fn mcfg() -> Option<Mcfg> {
+    tables()?.mcfg
+}
+
+/**
+ *  Gets the DSDT's bytes (header included), if found and valid.
+ *
+ *  Its AML code is left for callers to interpret.
+ */
+pub fn dsdt() -> Option<&'static [u8]> {
+    table(fadt()?.dsdt).filter(|dsdt| checksum(dsdt) == 0)
+}
+
+/**
+ *  Prints every table found, along with the parsed ones' contents.
+ */
+pub fn dump() {
+    match tables() {
+        Some(acpi) => println!("{}", acpi),
+        None => println!("ACPI: no tables found"),
+    }
+}
+
+/**
+ *  Everything found through the RSDP.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Acpi {
+    /// RSDP revision (0 for ACPI 1.0, 2 from ACPI 2.0 on).
+    pub revision: u8,
+
+    /// OEM supplying the tables.
+    pub oem_id: [u8; 6],
+
+    /// Headers of tables listed by RSDT/XSDT.
+    tables: [Option<TableInfo>; MAX_TABLES],
+
+    /// Multiple APIC Description Table.
+    pub madt: Option<Madt>,
+
+    /// Fixed ACPI Description Table.
+    pub fadt: Option<Fadt>,
+
+    /// High Precision Event Timer table.
+    pub hpet: Option<Hpet>,
+
+    /// PCI Express memory mapped configuration table.
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    /// Tables listed by RSDT/XSDT (up to `MAX_TABLES`).
+    pub fn tables(&self) -> impl Iterator<Item = &TableInfo> {
+        self.tables.iter().flatten()
+    }
+
+    /// Finds a listed table by its signature.
+    pub fn find(&self, signature: &[u8; 4]) -> Option<TableInfo> {
+        self.tables().find(|table| &table.signature == signature).copied()
+    }
+}
+
+/**
+ *  Header of a listed table.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    /// Identifies table's kind.
+    pub signature: [u8; 4],
+
+    /// Physical address of table.
+    pub address: PhysAddr,
+
+    /// Length in bytes, header included.
+    pub length: u32,
+
+    /// Table's own revision.
+    pub revision: u8,
+
+    /// If table's bytes sum up to zero, as they must.
+    pub valid: bool,
+}
+
+/**
+ *  Multiple APIC Description Table.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    /// Physical address of local APICs' registers.
+    pub local_apic_address: PhysAddr,
+
+    /// If legacy 8259 PICs are present as well.
+    pub pcat_compat: bool,
+
+    /// Local APIC of each processor.
+    local_apics: [Option<LocalApic>; MAX_LOCAL_APICS],
+
+    /// I/O APICs, each serving a range of GSIs.
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+
+    /// ISA IRQs not identity mapped to GSIs.
+    overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    /// Processors' local APICs.
+    pub fn local_apics(&self) -> impl Iterator<Item = &LocalApic> {
+        self.local_apics.iter().flatten()
+    }
+
+    /// I/O APICs.
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApic> {
+        self.io_apics.iter().flatten()
+    }
+
+    /// Interrupt source overrides.
+    pub fn overrides(&self) -> impl Iterator<Item = &InterruptOverride> {
+        self.overrides.iter().flatten()
+    }
+
+    /// Gets the GSI an ISA IRQ is wired to, considering overrides.
+    pub fn isa_irq_gsi(&self, irq: u8) -> u32 {
+        self.overrides()
+            .find(|over| over.source == irq)
+            .map_or(irq as u32, |over| over.gsi)
+    }
+}
+
+/**
+ *  Processor local APIC entry.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    /// ACPI processor ID.
+    pub processor_id: u8,
+
+    /// Local APIC ID.
+    pub apic_id: u8,
+
+    /// If processor is usable (or can be brought online).
+    pub enabled: bool,
+}
+
+/**
+ *  I/O APIC entry.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    /// I/O APIC ID.
+    pub id: u8,
+
+    /// Physical address of registers.
+    pub address: PhysAddr,
+
+    /// First GSI served by its redirection table.
+    pub gsi_base: u32,
+}
+
+/**
+ *  Interrupt source override entry.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    /// ISA IRQ being overridden.
+    pub source: u8,
+
+    /// GSI the IRQ is wired to instead.
+    pub gsi: u32,
+
+    /// MPS INTI flags (polarity in bits 0-1, trigger mode in 2-3).
+    pub flags: u16,
+}
+
+/**
+ *  Fixed ACPI Description Table (fields used by the kernel).
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Physical address of the DSDT.
+    pub dsdt: PhysAddr,
+
+    /// Interrupt vector (ISA IRQ) of the System Control Interrupt.
+    pub sci_interrupt: u16,
+
+    /// Port taking `acpi_enable` to switch into ACPI mode (0 if none).
+    pub smi_command: u32,
+
+    /// Value written to `smi_command` for enabling ACPI.
+    pub acpi_enable: u8,
+
+    /// Port of PM1a control register block.
+    pub pm1a_control: u32,
+
+    /// Port of PM1b control register block (0 if none).
+    pub pm1b_control: u32,
+
+    /// Port of power management timer (0 if none).
+    pub pm_timer: u32,
+
+    /// CMOS RTC index of century (0 if not supported).
+    pub century: u8,
+
+    /// IA-PC boot architecture flags (e.g. 8042 presence in bit 1).
+    pub boot_flags: u16,
+
+    /// Fixed feature flags (e.g. reset register support in bit 10).
+    pub flags: u32,
+
+    /// Register written with `reset_value` to reset the system.
+    pub reset_register: GenericAddress,
+
+    /// Value to write to `reset_register`.
+    pub reset_value: u8,
+}
+
+/**
+ *  High Precision Event Timer table.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// Hardware revision, number of comparators and vendor ID.
+    pub block_id: u32,
+
+    /// Event timer block's registers.
+    pub address: GenericAddress,
+
+    /// HPET sequence number.
+    pub number: u8,
+
+    /// Minimum clock ticks for periodic interrupts without losing any.
+    pub minimum_tick: u16,
+}
+
+/**
+ *  PCI Express memory mapped configuration table.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    /// Configuration spaces, one per PCI segment group.
+    segments: [Option<PciSegment>; MAX_PCI_SEGMENTS],
+}
+
+impl Mcfg {
+    /// Memory mapped configuration spaces.
+    pub fn segments(&self) -> impl Iterator<Item = &PciSegment> {
+        self.segments.iter().flatten()
+    }
+}
+
+/**
+ *  Memory mapped configuration space of a PCI segment group.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct PciSegment {
+    /// Physical address of the configuration space.
+    pub address: PhysAddr,
+
+    /// PCI segment group number.
+    pub segment: u16,
+
+    /// First bus decoded.
+    pub start_bus: u8,
+
+    /// Last bus decoded.
+    pub end_bus: u8,
+}
+
+/**
+ *  Register location, as described by ACPI.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    /// Address space (0 for memory, 1 for I/O ports).
+    pub address_space: u8,
+
+    /// Register size in bits.
+    pub bit_width: u8,
+
+    /// Register offset in bits.
+    pub bit_offset: u8,
+
+    /// Access size (1 for byte up to 4 for qword, 0 if undefined).
+    pub access_size: u8,
+
+    /// Address in its address space.
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// System memory address space.
+    pub const SYSTEM_MEMORY: u8 = 0;
+
+    /// System I/O (port) address space.
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/**
+ *  Errors returned on ACPI discovery.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// Physical memory isn't mapped yet.
+    MemoryNotInitialized,
+
+    /// No valid RSDP in BIOS areas.
+    RsdpNotFound,
+
+    /// RSDT/XSDT is truncated or its checksum is wrong.
+    InvalidRootTable,
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Size of the header shared by every table.
+const HEADER_SIZE: usize = 36;
+
+/// Largest table length trusted (DSDTs, the largest, take a few
+/// hundred KiB at most).
+const MAX_TABLE_LENGTH: usize = 1024 * 1024;
+
+/// Size of a page in bytes.
+const PAGE_SIZE: u64 = 4096;
+
+/// Tables found on `init`.
+static ACPI: spin::Mutex<Option<Acpi>> = spin::Mutex::new(None);
+
+/**
+ *  Finds the RSDP and walks its root table.
+ */
+fn parse() -> Result<Acpi, AcpiError> {
+    memory::phys_to_virt(PhysAddr::new(0))
+        .ok_or(AcpiError::MemoryNotInitialized)?;
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let revision = rsdp[15];
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&rsdp[9..15]);
+
+    // XSDT holds 64-bit pointers, RSDT 32-bit ones
+    let (root_addr, entry_size) = if revision >= 2 {
+        (read_u64(rsdp, 24).unwrap_or(0), 8)
+    } else {
+        (read_u32(rsdp, 16).unwrap_or(0) as u64, 4)
+    };
+    let root = PhysAddr::try_new(root_addr).ok()
+        .and_then(table)
+        .filter(|root| checksum(root) == 0)
+        .ok_or(AcpiError::InvalidRootTable)?;
+
+    let mut acpi = Acpi {
+        revision,
+        oem_id,
+        tables: [None; MAX_TABLES],
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+    };
+    let entries = root[HEADER_SIZE..].chunks_exact(entry_size);
+    for (slot, entry) in acpi.tables.iter_mut().zip(entries) {
+        let addr = match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0).map(u64::from),
+        };
+        let addr = match addr.and_then(|addr| PhysAddr::try_new(addr).ok()) {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let table = match table(addr) {
+            Some(table) => table,
+            None => continue,
+        };
+        let info = TableInfo {
+            signature: [table[0], table[1], table[2], table[3]],
+            address: addr,
+            length: table.len() as u32,
+            revision: table[8],
+            valid: checksum(table) == 0,
+        };
+        *slot = Some(info);
+        if !info.valid {
+            continue;
+        }
+        match &info.signature {
+            b"APIC" => acpi.madt = parse_madt(table),
+            b"FACP" => acpi.fadt = parse_fadt(table),
+            b"HPET" => acpi.hpet = parse_hpet(table),
+            b"MCFG" => acpi.mcfg = Some(parse_mcfg(table)),
+            _ => {}
+        }
+    }
+    Ok(acpi)
+}
+
+/**
+ *  Searches for the RSDP on 16-byte boundaries, first in the EBDA's
+ *  first KiB, then in BIOS read-only area, returning its bytes.
+ */
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = read_u16(phys_slice(PhysAddr::new(0x40e), 2)?, 0)? as u64;
+    let areas = [(ebda << 4, 1024), (0xe_0000, 0x2_0000)];
+    areas.iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, size)| (start..start + size).step_by(16))
+        .find_map(|addr| rsdp_at(PhysAddr::new(addr)))
+}
+
+/**
+ *  Gets the RSDP at `addr`, if there's a valid one.
+ *
+ *  ACPI 1.0 RSDPs have 20 bytes, whilst later ones have their length,
+ *  with a checksum covering the extended fields as well.
+ */
+fn rsdp_at(addr: PhysAddr) -> Option<&'static [u8]> {
+    let rsdp = phys_slice(addr, 20)?;
+    if &rsdp[..8] != b"RSD PTR " || checksum(rsdp) != 0 {
+        return None;
+    }
+    if rsdp[15] < 2 {
+        return Some(rsdp);
+    }
+    let length = read_u32(phys_slice(addr, 24)?, 20)? as usize;
+    if !(36..=MAX_TABLE_LENGTH).contains(&length) {
+        return None;
+    }
+    phys_slice(addr, length).filter(|rsdp| checksum(rsdp) == 0)
+}
+
+/**
+ *  Gets the bytes of the table at `addr`, as long as its header says.
+ */
+fn table(addr: PhysAddr) -> Option<&'static [u8]> {
+    let header = phys_slice(addr, HEADER_SIZE)?;
+    let length = read_u32(header, 4)? as usize;
+    if !(HEADER_SIZE..=MAX_TABLE_LENGTH).contains(&length) {
+        return None;
+    }
+    phys_slice(addr, length)
+}
+
+/**
+ *  Parses MADT's fixed fields and its variable length entries.
+ */
+fn parse_madt(table: &[u8]) -> Option<Madt> {
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_u32(table, 36)? as u64),
+        pcat_compat: read_u32(table, 40)? & 1 != 0,
+        local_apics: [None; MAX_LOCAL_APICS],
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+    let (mut local_apics, mut io_apics, mut overrides) = (0, 0, 0);
+    let mut offset = 44;
+    while offset + 2 <= table.len() {
+        let (kind, length) = (table[offset], table[offset + 1] as usize);
+        if length < 2 || offset + length > table.len() {
+            break;
+        }
+        let entry = &table[offset..offset + length];
+        match kind {
+            0 if local_apics < MAX_LOCAL_APICS => {
+                let flags = read_u32(entry, 4)?;
+                madt.local_apics[local_apics] = Some(LocalApic {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    enabled: flags & 0b11 != 0,
+                });
+                local_apics += 1;
+            }
+            1 if io_apics < MAX_IO_APICS => {
+                madt.io_apics[io_apics] = Some(IoApic {
+                    id: entry[2],
+                    address: PhysAddr::new(read_u32(entry, 4)? as u64),
+                    gsi_base: read_u32(entry, 8)?,
+                });
+                io_apics += 1;
+            }
+            2 if overrides < MAX_OVERRIDES => {
+                madt.overrides[overrides] = Some(InterruptOverride {
+                    source: entry[3],
+                    gsi: read_u32(entry, 4)?,
+                    flags: read_u16(entry, 8)?,
+                });
+                overrides += 1;
+            }
+            5 => {
+                let addr = read_u64(entry, 4)?;
+                madt.local_apic_address = PhysAddr::new(addr);
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    Some(madt)
+}
+
+/**
+ *  Parses FADT, whose later fields are missing on older revisions.
+ */
+fn parse_fadt(table: &[u8]) -> Option<Fadt> {
+    // 64-bit DSDT address takes precedence, if present
+    let dsdt = match read_u64(table, 140) {
+        Some(addr) if addr != 0 => addr,
+        _ => read_u32(table, 40)? as u64,
+    };
+    let reset_register = table.get(116..128)
+        .and_then(generic_address)
+        .unwrap_or(GenericAddress {
+            address_space: 0,
+            bit_width: 0,
+            bit_offset: 0,
+            access_size: 0,
+            address: 0,
+        });
+    Some(Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        sci_interrupt: read_u16(table, 46)?,
+        smi_command: read_u32(table, 48)?,
+        acpi_enable: *table.get(52)?,
+        pm1a_control: read_u32(table, 64)?,
+        pm1b_control: read_u32(table, 68)?,
+        pm_timer: read_u32(table, 76)?,
+        century: table.get(108).copied().unwrap_or(0),
+        boot_flags: read_u16(table, 109).unwrap_or(0),
+        flags: read_u32(table, 112).unwrap_or(0),
+        reset_register,
+        reset_value: table.get(128).copied().unwrap_or(0),
+    })
+}
+
+/**
+ *  Parses HPET table.
+ */
+fn parse_hpet(table: &[u8]) -> Option<Hpet> {
+    Some(Hpet {
+        block_id: read_u32(table, 36)?,
+        address: generic_address(table.get(40..52)?)?,
+        number: *table.get(52)?,
+        minimum_tick: read_u16(table, 53)?,
+    })
+}
+
+/**
+ *  Parses MCFG's configuration space entries.
+ */
+fn parse_mcfg(table: &[u8]) -> Mcfg {
+    let mut mcfg = Mcfg { segments: [None; MAX_PCI_SEGMENTS] };
+    let entries = table.get(44..).unwrap_or(&[]).chunks_exact(16);
+    for (slot, entry) in mcfg.segments.iter_mut().zip(entries) {
+        *slot = Some(PciSegment {
+            address: PhysAddr::new(read_u64(entry, 0).unwrap_or(0)),
+            segment: read_u16(entry, 8).unwrap_or(0),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        });
+    }
+    mcfg
+}
+
+/**
+ *  Decodes a 12-byte Generic Address Structure.
+ */
+fn generic_address(bytes: &[u8]) -> Option<GenericAddress> {
+    Some(GenericAddress {
+        address_space: *bytes.first()?,
+        bit_width: *bytes.get(1)?,
+        bit_offset: *bytes.get(2)?,
+        access_size: *bytes.get(3)?,
+        address: read_u64(bytes, 4)?,
+    })
+}
+
+/**
+ *  Sums bytes, wrapping around (valid ACPI structures sum up to zero).
+ */
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/**
+ *  Gets `len` bytes of physical memory from `addr`.
+ *
+ *  Returns `None` unless the whole range is mapped, as addresses
+ *  and lengths given by firmware can't be trusted.
+ */
+fn phys_slice(addr: PhysAddr, len: usize) -> Option<&'static [u8]> {
+    let virt = memory::phys_to_virt(addr)?;
+    let end = virt.as_u64().checked_add(len as u64)?;
+    let mapped = (virt.align_down(PAGE_SIZE).as_u64()..end)
+        .step_by(PAGE_SIZE as usize)
+        .all(|page| {
+            VirtAddr::try_new(page).ok()
+                .and_then(memory::translate_addr)
+                .is_some()
+        });
+    if !mapped {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(virt.as_ptr(), len) })
+}
+
+/// Reads a little endian `u16` at `offset`, if in bounds.
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+/// Reads a little endian `u32` at `offset`, if in bounds.
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Reads a little endian `u64` at `offset`, if in bounds.
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/**
+ *  Displays a signature or OEM ID, which should be ASCII.
+ */
+struct Ascii<'a>(&'a [u8]);
+
+impl fmt::Display for Ascii<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(str::from_utf8(self.0).unwrap_or("????"))
+    }
+}
+
+impl fmt::Display for Acpi {
+    /**
+     *  Formats the table list, followed by each parsed table.
+     */
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ACPI revision {}, OEM {}", self.revision,
+            Ascii(&self.oem_id))?;
+        for table in self.tables() {
+            write!(f, "\n  {} at {:#x}, {} bytes, rev {}{}",
+                Ascii(&table.signature), table.address.as_u64(),
+                table.length, table.revision,
+                if table.valid { "" } else { " (bad checksum)" })?;
+        }
+        if let Some(madt) = &self.madt {
+            write!(f, "\nMADT: local APIC at {:#x}{}",
+                madt.local_apic_address.as_u64(),
+                if madt.pcat_compat { ", with 8259 PICs" } else { "" })?;
+            for cpu in madt.local_apics() {
+                write!(f, "\n  CPU {}: APIC ID {}{}", cpu.processor_id,
+                    cpu.apic_id, if cpu.enabled { "" } else { " (off)" })?;
+            }
+            for io_apic in madt.io_apics() {
+                write!(f, "\n  I/O APIC {} at {:#x}, GSI base {}",
+                    io_apic.id, io_apic.address.as_u64(), io_apic.gsi_base)?;
+            }
+            for over in madt.overrides() {
+                write!(f, "\n  IRQ {} -> GSI {} (flags {:#06x})",
+                    over.source, over.gsi, over.flags)?;
+            }
+        }
+        if let Some(fadt) = &self.fadt {
+            write!(f, "\nFADT: DSDT at {:#x}, SCI IRQ {}, PM1a {:#x}, \
+                PM timer {:#x}, century {:#x}, reset {:#x} ({}) <- {:#x}",
+                fadt.dsdt.as_u64(), fadt.sci_interrupt, fadt.pm1a_control,
+                fadt.pm_timer, fadt.century, fadt.reset_register.address,
+                fadt.reset_register.address_space, fadt.reset_value)?;
+        }
+        if let Some(hpet) = &self.hpet {
+            write!(f, "\nHPET: #{} at {:#x}, block ID {:#010x}, \
+                minimum tick {}", hpet.number, hpet.address.address,
+                hpet.block_id, hpet.minimum_tick)?;
+        }
+        if let Some(mcfg) = &self.mcfg {
+            write!(f, "\nMCFG:")?;
+            for segment in mcfg.segments() {
+                write!(f, "\n  segment {} at {:#x}, buses {}-{}",
+                    segment.segment, segment.address.as_u64(),
+                    segment.start_bus, segment.end_bus)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Checks that QEMU's tables were found and make sense.
+ */
+#[test_case]
+fn test_tables() {
+    let acpi = tables().expect("ACPI tables not found");
+    assert!(acpi.tables().all(|table| table.valid));
+    assert!(acpi.find(b"FACP").is_some());
+
+    let madt = acpi.madt.expect("MADT not found");
+    assert!(madt.local_apics().any(|cpu| cpu.enabled));
+    assert!(madt.io_apics().count() >= 1);
+    let fadt = acpi.fadt.expect("FADT not found");
+    assert_ne!(fadt.pm1a_control, 0);
+}
+
+/**
+ *  Checks checksum validation and bounds checked reads.
+ */
+#[test_case]
+fn test_checksum() {
+    assert_eq!(checksum(&[0x10, 0xf0]), 0);
+    assert_ne!(checksum(&[0x10, 0xf1]), 0);
+    assert_eq!(read_u32(&[1, 0, 0, 0], 0), Some(1));
+    assert_eq!(read_u32(&[1, 0, 0], 0), None);
+}
+
+/**
+ *  Checks that slices of unmapped physical memory (as given by
+ *  corrupt entries) are refused.
+ */
+#[test_case]
+fn test_unmapped_slice() {
+    assert!(phys_slice(PhysAddr::new(0x1000), 16).is_some());
+    assert!(phys_slice(PhysAddr::new(1 << 45), 16).is_none());
+    assert!(phys_slice(PhysAddr::new(0x1000), usize::MAX).is_none());
+    assert!(table(PhysAddr::new(1 << 45)).is_none());
+}
@@ -0,0 +1,338 @@
+/*!
+ *  Kernel heap allocation.
+ *
+ *  Maps a virtual memory region as heap and registers a global
+ *  allocator on top of it, thus enabling `alloc` types (`Box`, `Vec`,
+ *  `String`, `BTreeMap`...) in the kernel. Whenever the backend runs
+ *  out of memory, the heap grows by mapping new pages right after
+ *  its end, up to a maximum size.
+ *
+ *  The allocator backend is chosen by exactly one cargo feature:
+ *
+ *  - `bump_allocator`: [`bump::BumpAllocator`];
+ *  - `linked_list_allocator` (default):
+ *    [`linked_list::LinkedListAllocator`];
+ *  - `fixed_size_block_allocator`:
+ *    [`fixed_size_block::FixedSizeBlockAllocator`].
+ */
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr
+};
+
+use crate::memory::{self, MapError};
+use crate::println;
+use crate::serial_println;
+
+pub mod bump;
+pub mod linked_list;
+pub mod fixed_size_block;
+
+#[cfg(feature = "bump_allocator")]
+use bump::BumpAllocator as Backend;
+
+#[cfg(feature = "linked_list_allocator")]
+use linked_list::LinkedListAllocator as Backend;
+
+#[cfg(feature = "fixed_size_block_allocator")]
+use fixed_size_block::FixedSizeBlockAllocator as Backend;
+
+#[cfg(not(any(
+    feature = "bump_allocator",
+    feature = "linked_list_allocator",
+    feature = "fixed_size_block_allocator",
+)))]
+compile_error!("no allocator backend feature selected");
+
+#[cfg(any(
+    all(feature = "bump_allocator", feature = "linked_list_allocator"),
+    all(feature = "bump_allocator", feature = "fixed_size_block_allocator"),
+    all(feature = "linked_list_allocator",
+        feature = "fixed_size_block_allocator"),
+))]
+compile_error!("only one allocator backend feature can be selected");
+
+/// Heap's starting virtual address.
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+
+/// Initial heap size in bytes (100 KiB).
+pub const HEAP_SIZE: usize = 100 * 1024;
+
+/// Default maximum size the heap can grow to (16 MiB).
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// Minimum number of bytes mapped each time the heap grows (64 KiB).
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+/**
+ *  Maps initial heap pages to newly allocated frames and
+ *  thereafter initializes the global allocator with them.
+ *
+ *  Requires `memory::init` to have been called beforehand.
+ */
+pub fn init_heap() -> Result<(), MapError> {
+    map_heap_pages(HEAP_START, HEAP_SIZE)?;
+    without_interrupts(|| {
+        let mut heap = ALLOCATOR.lock();
+        unsafe { heap.backend.init(HEAP_START, HEAP_SIZE) };
+        heap.stats.mapped = HEAP_SIZE;
+    });
+    Ok(())
+}
+
+/**
+ *  Sets maximum size (in bytes) the heap can grow to.
+ *
+ *  Values below currently mapped size just stop any further growth.
+ */
+pub fn set_max_size(max_size: usize) {
+    without_interrupts(|| {
+        ALLOCATOR.lock().stats.max_size = max_size;
+    });
+}
+
+/**
+ *  Gets a snapshot of current heap statistics.
+ */
+pub fn stats() -> HeapStats {
+    without_interrupts(|| ALLOCATOR.lock().stats)
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Global heap allocator, used by the `alloc` crate.
+ */
+#[global_allocator]
+static ALLOCATOR: Heap<Backend> = Heap::new(Backend::new());
+
+/**
+ *  Called when a heap allocation fails.
+ *
+ *  Prints failed layout on both VGA buffer and serial interface
+ *  before panicking (as the allocation can't be carried on).
+ */
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    println!("[ALLOC ERROR] {:?}", layout);
+    serial_println!("[ALLOC ERROR] {:?}", layout);
+    panic!("allocation error: {:?}", layout);
+}
+
+/**
+ *  Operations every heap allocator backend must provide.
+ */
+pub trait HeapBackend {
+    /**
+     *  Initializes backend with given heap bounds.
+     *
+     *  Unsafe since caller must guarantee that the memory range
+     *  is mapped and unused. Should be called only once.
+     */
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /**
+     *  Allocates memory fitting `layout`.
+     *
+     *  Returns a null pointer if there's no space left.
+     */
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /**
+     *  Frees memory previously given by `allocate`.
+     *
+     *  Unsafe since caller must guarantee that `ptr` was allocated
+     *  by this same backend with the given `layout`.
+     */
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+    /**
+     *  Makes `size` more bytes, right after current heap end, available.
+     *
+     *  Unsafe since caller must guarantee that the new memory range
+     *  is mapped and unused.
+     */
+    unsafe fn extend(&mut self, size: usize);
+}
+
+/**
+ *  Heap usage statistics.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently mapped as heap.
+    pub mapped: usize,
+
+    /// Maximum bytes the heap can grow to.
+    pub max_size: usize,
+
+    /// Bytes currently allocated (as requested by layouts).
+    pub in_use: usize,
+
+    /// Highest value `in_use` has reached.
+    pub peak: usize,
+
+    /// Number of allocations done so far.
+    pub allocations: usize,
+}
+
+impl fmt::Display for HeapStats {
+    /**
+     *  Formats stats in a single line, sizes given in KiB.
+     */
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+            "heap: {} KiB mapped (max {} KiB), {} KiB in use \
+            (peak {} KiB), {} allocations",
+            self.mapped / 1024, self.max_size / 1024,
+            self.in_use / 1024, self.peak / 1024, self.allocations)
+    }
+}
+
+/**
+ *  Growable heap, wrapping a backend and its statistics in a lock.
+ */
+struct Heap<B> {
+    /// The underlying locked heap state.
+    inner: spin::Mutex<HeapInner<B>>,
+}
+
+/**
+ *  Locked part of [`Heap`].
+ */
+struct HeapInner<B> {
+    /// Allocator backend.
+    backend: B,
+
+    /// Usage statistics.
+    stats: HeapStats,
+}
+
+impl<B: HeapBackend> Heap<B> {
+    /**
+     *  Wraps backend in a new (still unmapped) heap.
+     */
+    const fn new(backend: B) -> Self {
+        let stats = HeapStats {
+            mapped: 0,
+            max_size: HEAP_MAX_SIZE,
+            in_use: 0,
+            peak: 0,
+            allocations: 0,
+        };
+        Heap {
+            inner: spin::Mutex::new(HeapInner { backend, stats }),
+        }
+    }
+
+    /**
+     *  Locks heap state.
+     */
+    fn lock(&self) -> spin::MutexGuard<'_, HeapInner<B>> {
+        self.inner.lock()
+    }
+}
+
+impl<B: HeapBackend> HeapInner<B> {
+    /**
+     *  Maps enough new pages after heap end for `layout` to fit,
+     *  handing them to the backend.
+     *
+     *  Fails if heap would exceed its maximum size
+     *  or if pages couldn't be mapped.
+     */
+    fn grow(&mut self, layout: Layout) -> Result<(), ()> {
+        // Reserves room for worst case alignment padding and metadata
+        let needed = layout.size() + layout.align() + 64;
+        let size = align_up(needed.max(HEAP_GROWTH_STEP), 4096);
+        if self.stats.mapped + size > self.stats.max_size {
+            return Err(());
+        }
+        map_heap_pages(HEAP_START + self.stats.mapped, size)
+            .map_err(|_| ())?;
+        unsafe { self.backend.extend(size) };
+        self.stats.mapped += size;
+        Ok(())
+    }
+}
+
+unsafe impl<B: HeapBackend> GlobalAlloc for Heap<B> {
+    /**
+     *  Allocates from backend, growing heap and retrying on failure.
+     *
+     *  Interrupts are disabled meanwhile, so that handlers
+     *  which allocate can't deadlock on the heap lock.
+     */
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut heap = self.lock();
+            let mut ptr = heap.backend.allocate(layout);
+            while ptr.is_null() && heap.stats.mapped > 0 {
+                if heap.grow(layout).is_err() {
+                    break;
+                }
+                ptr = heap.backend.allocate(layout);
+            }
+            if !ptr.is_null() {
+                let stats = &mut heap.stats;
+                stats.in_use += layout.size();
+                stats.peak = stats.peak.max(stats.in_use);
+                stats.allocations += 1;
+            }
+            ptr
+        })
+    }
+
+    /**
+     *  Returns memory to backend.
+     */
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            let mut heap = self.lock();
+            heap.backend.deallocate(ptr, layout);
+            heap.stats.in_use -= layout.size();
+        });
+    }
+}
+
+/**
+ *  Maps `size` bytes of heap pages starting at `start`.
+ *
+ *  On failure, pages mapped so far are unmapped and their frames
+ *  freed, so that the same range can be mapped again later.
+ */
+fn map_heap_pages(start: usize, size: usize) -> Result<(), MapError> {
+    let heap_start = VirtAddr::new(start as u64);
+    let heap_end = heap_start + size - 1u64;
+    let page_range = Page::range_inclusive(
+        Page::containing_address(heap_start),
+        Page::containing_address(heap_end),
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in page_range {
+        if let Err(err) = unsafe { memory::map_page(page, flags) } {
+            for mapped in Page::range(page_range.start, page) {
+                unsafe {
+                    let frame = memory::unmap_page(mapped)?;
+                    memory::deallocate_frame(frame);
+                }
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/**
+ *  Aligns given address upwards to `align`.
+ *
+ *  `align` must be a power of two.
+ */
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
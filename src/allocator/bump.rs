@@ -0,0 +1,92 @@
+/*!
+ *  Bump heap allocator.
+ *
+ *  Simplest (and fastest) design: allocations are taken linearly
+ *  from the heap, which is only reclaimed as a whole once every
+ *  allocation has been freed.
+ */
+
+use core::alloc::Layout;
+use core::ptr;
+
+use super::{align_up, HeapBackend};
+
+/**
+ *  Allocator which "bumps" a pointer forward on each allocation.
+ */
+pub struct BumpAllocator {
+    /// Heap's starting address.
+    heap_start: usize,
+
+    /// Heap's (exclusive) ending address.
+    heap_end: usize,
+
+    /// Start address of next allocation.
+    next: usize,
+
+    /// Number of allocations not freed yet.
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    /**
+     *  Creates an empty allocator.
+     */
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl HeapBackend for BumpAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    /**
+     *  Takes next aligned address, bumping `next` past allocation.
+     *
+     *  Returns a null pointer if heap is exhausted.
+     */
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+        if alloc_end > self.heap_end {
+            return ptr::null_mut();
+        }
+        self.next = alloc_end;
+        self.allocations += 1;
+        alloc_start as *mut u8
+    }
+
+    /**
+     *  Just decrements allocations counter, resetting
+     *  `next` to heap's start when it reaches zero.
+     *
+     *  Freeing the most recent allocation also rolls `next` back.
+     */
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        } else if ptr as usize + layout.size() == self.next {
+            self.next = ptr as usize;
+        }
+    }
+
+    /**
+     *  Just moves heap end further.
+     */
+    unsafe fn extend(&mut self, size: usize) {
+        self.heap_end += size;
+    }
+}
@@ -0,0 +1,120 @@
+/*!
+ *  Fixed-size block heap allocator.
+ *
+ *  Rounds allocations up to a few block sizes, each with its own
+ *  list of free blocks, so both allocation and deallocation are
+ *  just list pushes and pops. Larger allocations (and new blocks)
+ *  are delegated to a fallback [`LinkedListAllocator`].
+ */
+
+use core::alloc::Layout;
+use core::mem;
+
+use super::linked_list::LinkedListAllocator;
+use super::HeapBackend;
+
+/**
+ *  Available block sizes, in bytes.
+ *
+ *  Each one is also used as block alignment, so they must be
+ *  powers of two (and at least large enough to hold a `ListNode`).
+ */
+const BLOCK_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/**
+ *  Node of a free blocks list, written at block's start.
+ */
+struct ListNode {
+    /// Next free block of same size, if any.
+    next: Option<&'static mut ListNode>,
+}
+
+/**
+ *  Allocator keeping a free list for each of [`BLOCK_SIZES`].
+ */
+pub struct FixedSizeBlockAllocator {
+    /// Heads of free lists, one per block size.
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+
+    /// Allocator used for large allocations and new blocks.
+    fallback: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    /**
+     *  Creates an empty allocator.
+     */
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback: LinkedListAllocator::new(),
+        }
+    }
+}
+
+impl HeapBackend for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback.init(heap_start, heap_size);
+    }
+
+    /**
+     *  Pops a block from respective free list, allocating a new one
+     *  from fallback if it's empty (or if layout fits no block size).
+     */
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // Block size doubles as its alignment
+                    let block_size = BLOCK_SIZES[index];
+                    let layout =
+                        Layout::from_size_align(block_size, block_size)
+                            .unwrap();
+                    self.fallback.allocate(layout)
+                }
+            },
+            None => self.fallback.allocate(layout),
+        }
+    }
+
+    /**
+     *  Pushes block to respective free list (or returns
+     *  it to fallback if layout fits no block size).
+     */
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // Checks that block can hold a node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => self.fallback.deallocate(ptr, layout),
+        }
+    }
+
+    /**
+     *  Hands new memory to fallback, where new blocks come from.
+     */
+    unsafe fn extend(&mut self, size: usize) {
+        self.fallback.extend(size);
+    }
+}
+
+/**
+ *  Chooses index of smallest block size fitting layout.
+ */
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
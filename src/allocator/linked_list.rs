@@ -0,0 +1,226 @@
+/*!
+ *  Linked list heap allocator.
+ *
+ *  Keeps track of free heap regions by storing a list node
+ *  inside each of them, thus needing no extra memory.
+ */
+
+use core::alloc::Layout;
+use core::{mem, ptr};
+
+use super::{align_up, HeapBackend};
+
+/**
+ *  Node of free regions list, written at region's start.
+ */
+struct ListNode {
+    /// Region size in bytes (node itself included).
+    size: usize,
+
+    /// Next free region, if any.
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    /**
+     *  Creates an unlinked node.
+     */
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    /// Region's starting address.
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Region's (exclusive) ending address.
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+
+    /**
+     *  Merges next region into this one if both are adjacent.
+     */
+    fn merge_next(&mut self) {
+        if let Some(next) = self.next.take() {
+            if self.end_addr() == next.start_addr() {
+                self.size += next.size;
+                self.next = next.next.take();
+            } else {
+                self.next = Some(next);
+            }
+        }
+    }
+}
+
+/**
+ *  Allocator which keeps a singly linked list of free regions,
+ *  taking the first one that fits on each allocation.
+ *
+ *  The list is kept sorted by address, so freed regions
+ *  are coalesced with their neighbours, avoiding fragmentation.
+ */
+pub struct LinkedListAllocator {
+    /// Dummy head node (of size 0) pointing to first free region.
+    head: ListNode,
+
+    /// Heap's (exclusive) ending address.
+    heap_end: usize,
+}
+
+impl LinkedListAllocator {
+    /**
+     *  Creates an empty allocator.
+     */
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode::new(0),
+            heap_end: 0,
+        }
+    }
+
+    /**
+     *  Inserts given memory region in the list (sorted by address),
+     *  merging it with adjacent free regions.
+     */
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // Region must be able to hold a `ListNode`
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // Finds last node placed before region
+        let mut current = &mut self.head;
+        while current.next.as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        // Extends previous region if adjacent (dummy head excluded);
+        // otherwise a new node is linked right after it
+        if current.size > 0 && current.end_addr() == addr {
+            current.size += size;
+            current.merge_next();
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = current.next.take();
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            (*node_ptr).merge_next();
+            current.next = Some(&mut *node_ptr);
+        }
+    }
+
+    /**
+     *  Looks for a free region fitting given size and alignment,
+     *  removing it from the list.
+     *
+     *  Returns region's node and allocation start address.
+     */
+    fn find_region(&mut self, size: usize, align: usize)
+        -> Option<(&'static mut ListNode, usize)>
+    {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            let result = Self::alloc_from_region(region, size, align);
+            if let Ok(alloc_start) = result {
+                let next = region.next.take();
+                let found = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return found;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+        None
+    }
+
+    /**
+     *  Tries to fit allocation into region, returning its start address.
+     *
+     *  Space left on both sides of the allocation must be either
+     *  empty or large enough to hold a `ListNode`; if the alignment
+     *  padding before it is too small, a further aligned address is tried.
+     */
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize)
+        -> Result<usize, ()>
+    {
+        let node_size = mem::size_of::<ListNode>();
+        let mut alloc_start = align_up(region.start_addr(), align);
+        if alloc_start > region.start_addr()
+            && alloc_start - region.start_addr() < node_size
+        {
+            alloc_start = align_up(region.start_addr() + node_size, align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < node_size {
+            return Err(());
+        }
+        Ok(alloc_start)
+    }
+
+    /**
+     *  Adjusts layout so that allocated region is also
+     *  able to store a `ListNode` when it's freed.
+     */
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl HeapBackend for LinkedListAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+        self.heap_end = heap_start + heap_size;
+    }
+
+    /**
+     *  Takes first fitting free region, returning
+     *  any space left before or after allocation back to the list.
+     *
+     *  Returns a null pointer if no region fits.
+     */
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = LinkedListAllocator::size_align(layout);
+        let (region, alloc_start) = match self.find_region(size, align) {
+            Some(found) => found,
+            None => return ptr::null_mut(),
+        };
+        let region_start = region.start_addr();
+        let region_end = region.end_addr();
+        let alloc_end = alloc_start + size;
+        unsafe {
+            if alloc_start > region_start {
+                self.add_free_region(region_start, alloc_start - region_start);
+            }
+            if region_end > alloc_end {
+                self.add_free_region(alloc_end, region_end - alloc_end);
+            }
+        }
+        alloc_start as *mut u8
+    }
+
+    /**
+     *  Returns allocated region back to the list.
+     */
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+
+    /**
+     *  Adds new memory as a free region (merged with last one if free).
+     */
+    unsafe fn extend(&mut self, size: usize) {
+        self.add_free_region(self.heap_end, size);
+        self.heap_end += size;
+    }
+}
@@ -0,0 +1,519 @@
+/*!
+ *  Local APIC and I/O APIC interrupt controllers.
+ *
+ *  The local APIC receives interrupts on behalf of its CPU (and takes
+ *  their EOIs), whilst the I/O APIC routes external interrupt lines,
+ *  numbered as Global System Interrupts (GSIs), to vectors through its
+ *  redirection table. Both are programmed through uncached MMIO
+ *  registers, mapped by `init`.
+ *
+ *  Legacy ISA IRQs are mostly wired to the same numbered GSI, with
+ *  exceptions listed by the ACPI MADT as interrupt source overrides
+ *  (which may also make them level triggered or active low). MADT
+ *  also gives the I/O APIC's address and the first GSI it serves
+ *  (only the first one is used). Without ACPI, usual defaults are
+ *  assumed instead.
+ */
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::model_specific::Msr,
+    PhysAddr, VirtAddr
+};
+
+use crate::acpi;
+use crate::memory::{self, MapError};
+
+/// Vector delivered by the local APIC on spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Usual physical address of the I/O APIC registers (if no MADT).
+pub const IO_APIC_ADDRESS: u64 = 0xfec0_0000;
+
+/**
+ *  If CPU has an on-chip local APIC, as reported by CPUID.
+ */
+pub fn is_supported() -> bool {
+    let edx = unsafe { __cpuid(1).edx };
+    edx & (1 << 9) != 0
+}
+
+/**
+ *  If `init` has succeeded, so that APIC is taking interrupts.
+ */
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Acquire) != 0
+}
+
+/**
+ *  Enables local APIC and masks every I/O APIC redirection entry.
+ *
+ *  Unsafe since legacy PICs must be disabled beforehand, otherwise
+ *  both controllers end up delivering the same interrupts.
+ */
+pub unsafe fn init() -> Result<(), ApicError> {
+    if !is_supported() {
+        return Err(ApicError::Unsupported);
+    }
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = base_msr.read();
+    base_msr.write(base | APIC_GLOBAL_ENABLE);
+    let phys_addr = PhysAddr::new(base & APIC_BASE_MASK);
+    let local_apic = memory::map_mmio(phys_addr, 4096)?;
+
+    // Accepts every priority and masks local interrupt sources,
+    // then software enables APIC along with its spurious vector
+    write_local(local_apic, TPR, 0);
+    write_local(local_apic, LVT_TIMER, LVT_MASKED);
+    write_local(local_apic, LVT_ERROR, LVT_MASKED);
+    write_local(local_apic, SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let (io_apic_addr, gsi_base) = acpi::madt()
+        .and_then(|madt| {
+            madt.io_apics().next().map(|io| (io.address, io.gsi_base))
+        })
+        .unwrap_or((PhysAddr::new(IO_APIC_ADDRESS), 0));
+    let io_apic =
+        IoApic::new(memory::map_mmio(io_apic_addr, 4096)?, gsi_base);
+    for index in 0..io_apic.entries() {
+        io_apic.write_entry(index, RedirectionEntry::MASKED);
+    }
+    without_interrupts(|| *IO_APIC.lock() = Some(io_apic));
+    LOCAL_APIC.store(local_apic.as_u64(), Ordering::Release);
+    Ok(())
+}
+
+/**
+ *  Signals end of interrupt to local APIC.
+ *
+ *  Lock-free, so it's safe to call from any interrupt handler.
+ */
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::Acquire);
+    if base != 0 {
+        unsafe { write_local(VirtAddr::new(base), EOI, 0) };
+    }
+}
+
+/**
+ *  Gets the local APIC's ID, if enabled.
+ */
+pub fn local_apic_id() -> Option<u8> {
+    read_local_register(ID).map(|id| (id >> 24) as u8)
+}
+
+/**
+ *  Gets the local APIC's version register, if enabled
+ *  (version in bits 0-7, number of LVT entries minus one in 16-23).
+ */
+pub fn local_apic_version() -> Option<u32> {
+    read_local_register(VERSION)
+}
+
+/**
+ *  Starts local APIC timer, counting down from `initial_count` (at
+ *  bus frequency divided by 16) and raising `vector` on reaching zero.
+ *
+ *  Periodic timers reload `initial_count`, whilst one-shot ones stop.
+ */
+pub fn start_timer(vector: u8, initial_count: u32, periodic: bool)
+    -> Result<(), ApicError>
+{
+    let mode = if periodic { LVT_TIMER_PERIODIC } else { 0 };
+    write_local_register(TIMER_DIVIDE, TIMER_DIVIDE_BY_16)?;
+    write_local_register(LVT_TIMER, vector as u32 | mode)?;
+    write_local_register(TIMER_INITIAL_COUNT, initial_count)
+}
+
+/**
+ *  Stops and masks local APIC timer.
+ */
+pub fn stop_timer() -> Result<(), ApicError> {
+    write_local_register(TIMER_INITIAL_COUNT, 0)?;
+    write_local_register(LVT_TIMER, LVT_MASKED)
+}
+
+/**
+ *  Gets local APIC timer's current count, if enabled.
+ */
+pub fn timer_count() -> Option<u32> {
+    read_local_register(TIMER_CURRENT_COUNT)
+}
+
+/**
+ *  Gets the GSI an ISA IRQ is wired to.
+ *
+ *  Without a MADT, PIT timer is assumed to be on GSI 2 (as usual),
+ *  and every other IRQ on the same numbered GSI.
+ */
+pub fn isa_irq_gsi(irq: u8) -> u32 {
+    match (acpi::madt(), irq) {
+        (Some(madt), irq) => madt.isa_irq_gsi(irq),
+        (None, 0) => 2,
+        (None, irq) => irq as u32,
+    }
+}
+
+/**
+ *  Routes `gsi` to `vector` on current CPU's local APIC, unmasking it.
+ *
+ *  Entry is edge triggered and active high, as for ISA IRQs, unless
+ *  an interrupt source override onto `gsi` says otherwise.
+ */
+pub fn route_irq(gsi: u32, vector: u8) -> Result<(), ApicError> {
+    let destination = local_apic_id().ok_or(ApicError::NotEnabled)?;
+    let flags = acpi::madt()
+        .and_then(|madt| {
+            madt.overrides().find(|over| over.gsi == gsi).map(|over| over.flags)
+        })
+        .unwrap_or(0);
+    let entry = RedirectionEntry::new(vector, destination)
+        .with_inti_flags(flags);
+    with_io_apic(gsi, |io_apic, index| io_apic.write_entry(index, entry))
+}
+
+/**
+ *  Masks `gsi`, keeping the rest of its redirection entry.
+ */
+pub fn mask_irq(gsi: u32) -> Result<(), ApicError> {
+    with_io_apic(gsi, |io_apic, index| {
+        let entry = io_apic.read_entry(index);
+        io_apic.write_entry(index, entry.with_mask(true));
+    })
+}
+
+/**
+ *  Unmasks `gsi`, keeping the rest of its redirection entry.
+ */
+pub fn unmask_irq(gsi: u32) -> Result<(), ApicError> {
+    with_io_apic(gsi, |io_apic, index| {
+        let entry = io_apic.read_entry(index);
+        io_apic.write_entry(index, entry.with_mask(false));
+    })
+}
+
+/**
+ *  Gets the redirection entry of `gsi`.
+ */
+pub fn redirection_entry(gsi: u32) -> Result<RedirectionEntry, ApicError> {
+    with_io_apic(gsi, |io_apic, index| io_apic.read_entry(index))
+}
+
+/**
+ *  I/O APIC redirection table entry.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry(u64);
+
+impl RedirectionEntry {
+    /// Masked entry, delivering nothing.
+    const MASKED: RedirectionEntry = RedirectionEntry(1 << 16);
+
+    /// Fixed delivery, physical destination, edge triggered, active high.
+    fn new(vector: u8, destination: u8) -> Self {
+        RedirectionEntry(vector as u64 | (destination as u64) << 56)
+    }
+
+    /// Vector delivered to CPU.
+    pub fn vector(&self) -> u8 {
+        self.0 as u8
+    }
+
+    /// APIC ID of destination CPU.
+    pub fn destination(&self) -> u8 {
+        (self.0 >> 56) as u8
+    }
+
+    /// If interrupt line is masked.
+    pub fn is_masked(&self) -> bool {
+        self.0 & (1 << 16) != 0
+    }
+
+    /// If interrupt line is active low (instead of high).
+    pub fn is_active_low(&self) -> bool {
+        self.0 & ACTIVE_LOW != 0
+    }
+
+    /// If interrupt line is level (instead of edge) triggered.
+    pub fn is_level_triggered(&self) -> bool {
+        self.0 & LEVEL_TRIGGERED != 0
+    }
+
+    /**
+     *  Same entry, with polarity and trigger mode of MPS INTI
+     *  `flags`, where "conforming to bus" means ISA's (active
+     *  high, edge triggered).
+     */
+    fn with_inti_flags(self, flags: u16) -> Self {
+        let mut entry = self.0 & !(ACTIVE_LOW | LEVEL_TRIGGERED);
+        if flags & INTI_POLARITY == INTI_ACTIVE_LOW {
+            entry |= ACTIVE_LOW;
+        }
+        if flags & INTI_TRIGGER == INTI_LEVEL_TRIGGERED {
+            entry |= LEVEL_TRIGGERED;
+        }
+        RedirectionEntry(entry)
+    }
+
+    /// Same entry, masked or unmasked.
+    fn with_mask(self, masked: bool) -> Self {
+        if masked {
+            RedirectionEntry(self.0 | 1 << 16)
+        } else {
+            RedirectionEntry(self.0 & !(1 << 16))
+        }
+    }
+}
+
+/**
+ *  Errors returned on APIC operations.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// CPU has no local APIC.
+    Unsupported,
+
+    /// `init` hasn't succeeded.
+    NotEnabled,
+
+    /// GSI is outside I/O APIC's redirection table.
+    InvalidGsi(u32),
+
+    /// Mapping controller registers failed.
+    MapFailed(MapError),
+}
+
+impl From<MapError> for ApicError {
+    fn from(err: MapError) -> Self {
+        ApicError::MapFailed(err)
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// MSR holding local APIC's physical base address.
+const IA32_APIC_BASE: u32 = 0x1b;
+
+/// Global enable bit of `IA32_APIC_BASE`.
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+/// Base address bits of `IA32_APIC_BASE`.
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Local APIC register offsets.
+const ID: u64 = 0x20;
+const VERSION: u64 = 0x30;
+const TPR: u64 = 0x80;
+const EOI: u64 = 0xb0;
+const SVR: u64 = 0xf0;
+const LVT_TIMER: u64 = 0x320;
+const LVT_ERROR: u64 = 0x370;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+/// APIC software enable bit of spurious vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// Mask bit of local vector table entries.
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Periodic mode bit of LVT timer entry.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// Timer divide configuration dividing bus frequency by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Polarity and trigger mode bits of redirection entries.
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+
+/// MPS INTI flags' polarity and trigger mode fields, and their values.
+const INTI_POLARITY: u16 = 0b11;
+const INTI_ACTIVE_LOW: u16 = 0b11;
+const INTI_TRIGGER: u16 = 0b11 << 2;
+const INTI_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+/// Virtual base address of local APIC registers (zero if disabled).
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// I/O APIC, once initialized.
+static IO_APIC: spin::Mutex<Option<IoApic>> = spin::Mutex::new(None);
+
+/**
+ *  I/O APIC, accessed indirectly through a register select
+ *  and a data window.
+ */
+struct IoApic {
+    /// Virtual base address of registers.
+    base: VirtAddr,
+
+    /// GSI of the first redirection entry.
+    gsi_base: u32,
+}
+
+impl IoApic {
+    /// Offset of register select (IOREGSEL).
+    const SELECT: u64 = 0x00;
+
+    /// Offset of data window (IOWIN).
+    const WINDOW: u64 = 0x10;
+
+    /// Version register, holding maximum redirection entry (bits 16-23).
+    const VERSION: u32 = 0x01;
+
+    /// First redirection table register (each entry takes two).
+    const REDIRECTION_TABLE: u32 = 0x10;
+
+    /// Wraps already mapped registers, serving GSIs from `gsi_base`.
+    fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        IoApic { base, gsi_base }
+    }
+
+    /// Number of redirection entries.
+    fn entries(&self) -> u32 {
+        ((self.read(Self::VERSION) >> 16) & 0xff) + 1
+    }
+
+    /// Reads redirection entry `index`.
+    fn read_entry(&self, index: u32) -> RedirectionEntry {
+        let register = Self::REDIRECTION_TABLE + 2 * index;
+        let low = self.read(register) as u64;
+        let high = self.read(register + 1) as u64;
+        RedirectionEntry(high << 32 | low)
+    }
+
+    /// Writes redirection entry `index`, masking it meanwhile.
+    fn write_entry(&self, index: u32, entry: RedirectionEntry) {
+        let register = Self::REDIRECTION_TABLE + 2 * index;
+        self.write(register, RedirectionEntry::MASKED.0 as u32);
+        self.write(register + 1, (entry.0 >> 32) as u32);
+        self.write(register, entry.0 as u32);
+    }
+
+    /// Reads a register through the data window.
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            let select: *mut u32 = (self.base + Self::SELECT).as_mut_ptr();
+            let window: *const u32 = (self.base + Self::WINDOW).as_ptr();
+            select.write_volatile(register);
+            window.read_volatile()
+        }
+    }
+
+    /// Writes a register through the data window.
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            let select: *mut u32 = (self.base + Self::SELECT).as_mut_ptr();
+            let window: *mut u32 = (self.base + Self::WINDOW).as_mut_ptr();
+            select.write_volatile(register);
+            window.write_volatile(value);
+        }
+    }
+}
+
+/**
+ *  Runs `f` with the I/O APIC and the index of `gsi`'s redirection
+ *  entry, checking that `gsi` is in its table.
+ */
+fn with_io_apic<F, T>(gsi: u32, f: F) -> Result<T, ApicError>
+where
+    F: FnOnce(&IoApic, u32) -> T,
+{
+    without_interrupts(|| {
+        let io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_ref().ok_or(ApicError::NotEnabled)?;
+        match gsi.checked_sub(io_apic.gsi_base) {
+            Some(index) if index < io_apic.entries() =>
+                Ok(f(io_apic, index)),
+            _ => Err(ApicError::InvalidGsi(gsi)),
+        }
+    })
+}
+
+/**
+ *  Reads a local APIC register, if enabled.
+ */
+fn read_local_register(offset: u64) -> Option<u32> {
+    let base = LOCAL_APIC.load(Ordering::Acquire);
+    if base == 0 {
+        return None;
+    }
+    let ptr: *const u32 = (VirtAddr::new(base) + offset).as_ptr();
+    Some(unsafe { ptr.read_volatile() })
+}
+
+/**
+ *  Writes a local APIC register, if enabled.
+ */
+fn write_local_register(offset: u64, value: u32) -> Result<(), ApicError> {
+    let base = LOCAL_APIC.load(Ordering::Acquire);
+    if base == 0 {
+        return Err(ApicError::NotEnabled);
+    }
+    unsafe { write_local(VirtAddr::new(base), offset, value) };
+    Ok(())
+}
+
+/**
+ *  Writes a local APIC register, given registers' base.
+ */
+unsafe fn write_local(base: VirtAddr, offset: u64, value: u32) {
+    let ptr: *mut u32 = (base + offset).as_mut_ptr();
+    ptr.write_volatile(value);
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Checks that local APIC registers read sane values once enabled.
+ */
+#[test_case]
+fn test_local_apic() {
+    if !is_enabled() {
+        return;
+    }
+    let version = local_apic_version().unwrap();
+    // Integrated APICs have versions 0x10 to 0x15
+    assert!((0x10..=0x15).contains(&(version & 0xff)));
+    assert!(local_apic_id().is_some());
+}
+
+/**
+ *  Masks an unused entry (already masked by `init`),
+ *  which must be left unchanged.
+ */
+#[test_case]
+fn test_mask_irq() {
+    const GSI: u32 = 15;  // ISA IRQ 15, secondary ATA
+    if !is_enabled() {
+        assert_eq!(mask_irq(GSI), Err(ApicError::NotEnabled));
+        return;
+    }
+    let entry = redirection_entry(GSI).unwrap();
+    assert!(entry.is_masked());
+    assert_eq!(mask_irq(GSI), Ok(()));
+    assert_eq!(redirection_entry(GSI), Ok(entry));
+    assert_eq!(redirection_entry(1024), Err(ApicError::InvalidGsi(1024)));
+}
+
+/**
+ *  Applies MPS INTI flags to an entry, checking its polarity
+ *  and trigger mode.
+ */
+#[test_case]
+fn test_inti_flags() {
+    let entry = RedirectionEntry::new(0x30, 0);
+    let conforming = entry.with_inti_flags(0);
+    assert!(!conforming.is_active_low() && !conforming.is_level_triggered());
+    // As usual for the SCI
+    let sci = entry.with_inti_flags(INTI_ACTIVE_LOW | INTI_LEVEL_TRIGGERED);
+    assert!(sci.is_active_low() && sci.is_level_triggered());
+    assert_eq!(sci.vector(), 0x30);
+    // Active high, edge triggered explicitly
+    let edge = sci.with_inti_flags(0b01 | 0b01 << 2);
+    assert_eq!(edge, entry);
+}
@@ -0,0 +1,197 @@
+/*!
+ *  Stack backtraces, based on frame pointers and symbolized
+ *  through the kernel symbol table.
+ *
+ *  Kernel is built keeping frame pointers, so every function
+ *  prologue pushes caller's RBP and then points RBP to it.
+ *  Each frame thus starts with the following pair, forming a chain:
+ *
+ *  | Address   | Content                                        |
+ *  |-----------|------------------------------------------------|
+ *  | RBP + 8   | return address into caller                     |
+ *  | RBP       | caller's RBP                                   |
+ *
+ *  Frames are only followed whilst lying inside a known stack,
+ *  so that corrupted chains end the walk instead of faulting.
+ */
+
+use core::fmt;
+
+use x86_64::VirtAddr;
+
+use crate::stack;
+use crate::symbols;
+
+/// Maximum number of frames kept by a backtrace.
+pub const MAX_FRAMES: usize = 16;
+
+/**
+ *  Sequence of code addresses, innermost first.
+ */
+pub struct Backtrace {
+    /// Instruction and return addresses.
+    frames: [u64; MAX_FRAMES],
+
+    /// Number of valid addresses in `frames`.
+    len: usize,
+
+    /// If walk stopped due to `MAX_FRAMES` being reached.
+    truncated: bool,
+
+    /// If first address is an instruction (not a return) address.
+    has_ip: bool,
+}
+
+impl Backtrace {
+    /**
+     *  Walks current stack, starting from caller's return address.
+     */
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp,
+                options(nomem, nostack, preserves_flags));
+        }
+        let mut backtrace = Backtrace::new();
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /**
+     *  Walks stack of interrupted code, given its
+     *  instruction pointer (kept as first frame) and RBP.
+     */
+    pub fn from_frame(rip: u64, rbp: u64) -> Self {
+        let mut backtrace = Backtrace::new();
+        backtrace.push(rip);
+        backtrace.has_ip = true;
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /**
+     *  Gets addresses found, innermost first.
+     */
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    /**
+     *  If more frames existed than could be kept.
+     */
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+impl Backtrace {
+    /**
+     *  Creates an empty backtrace.
+     */
+    fn new() -> Self {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            truncated: false,
+            has_ip: false,
+        }
+    }
+
+    /**
+     *  Appends an address, unless backtrace is already full.
+     */
+    fn push(&mut self, addr: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            self.truncated = true;
+            return false;
+        }
+        self.frames[self.len] = addr;
+        self.len += 1;
+        true
+    }
+
+    /**
+     *  Follows RBP chain, appending return addresses.
+     *
+     *  Each frame must be aligned and fully inside the stack holding
+     *  the first one, and callers' frames must lie strictly above
+     *  their callees' (stack grows downwards), so walk always ends.
+     */
+    fn walk(&mut self, mut rbp: u64) {
+        let stack = match VirtAddr::try_new(rbp).ok()
+            .and_then(stack::containing)
+        {
+            Some(stack) => stack,
+            None => return,
+        };
+        let (bottom, top) = (stack.bottom.as_u64(), stack.top.as_u64());
+        while rbp % 8 == 0 && rbp >= bottom && rbp + 16 <= top {
+            let frame = rbp as *const u64;
+            let (next_rbp, return_addr) =
+                unsafe { (frame.read(), frame.add(1).read()) };
+            if return_addr == 0 || !self.push(return_addr) {
+                break;
+            }
+            if next_rbp <= rbp {
+                break;
+            }
+            rbp = next_rbp;
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    /**
+     *  Formats addresses one per line, numbered from innermost,
+     *  followed by `function+offset` when found in symbol table.
+     */
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+        for (i, &addr) in self.frames().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#018x}", i, addr)?;
+
+            // Return address might be right past a call ending function
+            let is_ip = i == 0 && self.has_ip;
+            let lookup_addr = if is_ip { addr } else { addr - 1 };
+            if let Some((symbol, _)) = symbols::resolve(lookup_addr) {
+                write!(f, " {}+{:#x}", symbol.name, addr - symbol.address)?;
+            }
+        }
+        if self.truncated {
+            write!(f, "\n  ...")?;
+        }
+        Ok(())
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Captures a backtrace from a non-inlined function, so that
+ *  at least its return address and test's are found.
+ */
+#[test_case]
+fn test_capture() {
+    #[inline(never)]
+    fn nested() -> Backtrace {
+        Backtrace::capture()
+    }
+
+    let backtrace = nested();
+    assert!(backtrace.frames().len() >= 2);
+    assert!(backtrace.frames().iter().all(|&addr| addr != 0));
+}
+
+/**
+ *  Checks that a frame pointer outside known stacks
+ *  ends the walk right away.
+ */
+#[test_case]
+fn test_invalid_frame_pointer() {
+    let backtrace = Backtrace::from_frame(0x1234, 0xdead_beef);
+    assert_eq!(backtrace.frames(), &[0x1234]);
+    assert!(!backtrace.is_truncated());
+}
@@ -0,0 +1,168 @@
+/*!
+ *  Register context of interrupted code.
+ *
+ *  Exception entry points are naked trampolines which push every
+ *  general purpose register on the stack, right below the frame
+ *  pushed by the CPU, and hand a [`Context`] pointing there to
+ *  their Rust handler. Any change made to the context by the handler
+ *  (e.g. to `rip`) takes effect when the interrupted code is resumed.
+ */
+
+use core::fmt;
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+
+/**
+ *  Registers saved on exception entry, laid out as on the stack
+ *  (i.e. in reverse order of pushing).
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// Error code pushed by the CPU (zero for exceptions without one).
+    pub error_code: u64,
+
+    /// Instruction pointer to resume execution at.
+    pub rip: u64,
+
+    /// Code segment selector of interrupted code.
+    pub cs: u64,
+
+    /// RFLAGS of interrupted code.
+    pub rflags: u64,
+
+    /// Stack pointer of interrupted code.
+    pub rsp: u64,
+
+    /// Stack segment selector of interrupted code.
+    pub ss: u64,
+}
+
+/**
+ *  Defines `$entry`, a function returning a naked trampoline (as IDT
+ *  handler type `$ty`) which saves the register [`Context`] and calls
+ *  `$handler: extern "C" fn(&mut Context)` with it.
+ *
+ *  Exceptions without error code must be marked with `no_error_code`,
+ *  so that a zero is pushed in its place, keeping the same layout.
+ */
+macro_rules! exception_entry {
+    ($entry:ident, $handler:path, $ty:ty) => {
+        exception_entry!(@define $entry, $handler, $ty, );
+    };
+    ($entry:ident, $handler:path, $ty:ty, no_error_code) => {
+        exception_entry!(@define $entry, $handler, $ty, "push 0",);
+    };
+    (@define $entry:ident, $handler:path, $ty:ty, $($push:literal,)?) => {
+        pub fn $entry() -> $ty {
+            #[naked]
+            unsafe extern "C" fn trampoline() -> ! {
+                asm!(
+                    $($push,)?
+                    "push rax", "push rbx", "push rcx", "push rdx",
+                    "push rsi", "push rdi", "push rbp", "push r8",
+                    "push r9", "push r10", "push r11", "push r12",
+                    "push r13", "push r14", "push r15",
+                    // CPU frame, error code and 15 registers leave the
+                    // stack 8 bytes off the 16-byte call alignment
+                    "mov rdi, rsp",
+                    "sub rsp, 8",
+                    "cld",
+                    "call {handler}",
+                    "add rsp, 8",
+                    "pop r15", "pop r14", "pop r13", "pop r12",
+                    "pop r11", "pop r10", "pop r9", "pop r8",
+                    "pop rbp", "pop rdi", "pop rsi", "pop rdx",
+                    "pop rcx", "pop rbx", "pop rax",
+                    // Discards error code
+                    "add rsp, 8",
+                    "iretq",
+                    handler = sym $handler,
+                    options(noreturn)
+                );
+            }
+            let trampoline: unsafe extern "C" fn() -> ! = trampoline;
+            unsafe { core::mem::transmute(trampoline) }
+        }
+    };
+}
+
+pub(crate) use exception_entry;
+
+/*---------------------------------------------------------------------------*/
+
+/// RFLAGS bits and their names, from lowest to highest.
+const RFLAGS_BITS: [(u8, &str); 15] = [
+    (0, "CF"), (2, "PF"), (4, "AF"), (6, "ZF"), (7, "SF"),
+    (8, "TF"), (9, "IF"), (10, "DF"), (11, "OF"), (14, "NT"),
+    (16, "RF"), (17, "VM"), (18, "AC"), (19, "VIF"), (20, "VIP"),
+];
+
+impl fmt::Display for Context {
+    /**
+     *  Formats registers in aligned rows fitting the VGA buffer width.
+     *
+     *  Control registers, EFER and data segment selectors aren't saved
+     *  on entry, so their current values are shown instead.
+     */
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            [("RAX", self.rax), ("RBX", self.rbx), ("RCX", self.rcx)],
+            [("RDX", self.rdx), ("RSI", self.rsi), ("RDI", self.rdi)],
+            [("RBP", self.rbp), ("RSP", self.rsp), ("R8", self.r8)],
+            [("R9", self.r9), ("R10", self.r10), ("R11", self.r11)],
+            [("R12", self.r12), ("R13", self.r13), ("R14", self.r14)],
+            [("R15", self.r15), ("RIP", self.rip),
+                ("ERR", self.error_code)],
+            [("CR0", Cr0::read_raw()), ("CR2", Cr2::read().as_u64()),
+                ("CR3", Cr3::read().0.start_address().as_u64())],
+            [("CR4", Cr4::read_raw()), ("EFER", Efer::read_raw()),
+                ("RFL", self.rflags)],
+        ];
+        for row in rows.iter() {
+            for (i, (name, value)) in row.iter().enumerate() {
+                let sep = if i + 1 < row.len() { " " } else { "\n" };
+                write!(f, "{:<4}={:016x}{}", name, value, sep)?;
+            }
+        }
+        write!(f, "FLAGS=[")?;
+        let mut first = true;
+        for &(bit, name) in RFLAGS_BITS.iter() {
+            if self.rflags & (1 << bit) != 0 {
+                write!(f, "{}{}", if first { "" } else { " " }, name)?;
+                first = false;
+            }
+        }
+        writeln!(f, "] IOPL={}", (self.rflags >> 12) & 0b11)?;
+
+        let (ds, es, fs, gs): (u16, u16, u16, u16);
+        unsafe {
+            asm!(
+                "mov {0:x}, ds", "mov {1:x}, es",
+                "mov {2:x}, fs", "mov {3:x}, gs",
+                out(reg) ds, out(reg) es, out(reg) fs, out(reg) gs,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        write!(f, "CS={:04x} SS={:04x} DS={:04x} ES={:04x} \
+            FS={:04x} GS={:04x}",
+            self.cs, self.ss, ds, es, fs, gs)
+    }
+}
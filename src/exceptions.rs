@@ -1,17 +1,72 @@
 /*!
  *  CPU exceptions handling.
+ *
+ *  Every architectural exception has its own handler, which prints
+ *  exception's name, decoded error code (if any) and stack frame.
+ *  Traps (e.g. breakpoints) return normally, whilst faults are fatal.
+ *
+ *  Faults are entered through trampolines saving the full register
+ *  [`Context`], which is dumped (along with a backtrace) on both
+ *  VGA buffer and serial interface before panicking.
  */
 
+use core::fmt;
+#[cfg(test)]
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use x86_64::structures::idt::{
-    InterruptStackFrame, PageFaultErrorCode
+    DivergingHandlerFunc, DivergingHandlerFuncWithErrCode, HandlerFunc,
+    HandlerFuncWithErrCode, InterruptStackFrame, PageFaultErrorCode,
+    PageFaultHandlerFunc
 };
 
+use crate::backtrace::Backtrace;
+use crate::context::{exception_entry, Context};
+use crate::page_fault;
 use crate::println;
 use crate::serial_println;
 use crate::qemu;
+use crate::stack;
 
 /**
- *  Breakpoint exception handler.
+ *  Divide Error (#DE) exception handler.
+ *
+ *  Triggered by a division by zero or a quotient too large
+ *  to fit in destination operand (`DIV` and `IDIV` instructions).
+ */
+extern "C" fn divide_error_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] DIVIDE ERROR");
+    fault(context);
+}
+
+exception_entry!(divide_error_entry, divide_error_handler,
+    HandlerFunc, no_error_code);
+
+/**
+ *  Debug (#DB) exception handler.
+ *
+ *  Triggered by debug conditions such as single-stepping,
+ *  hardware breakpoints or an INT1 instruction.
+ */
+pub extern "x86-interrupt" fn debug_handler(
+    stack_frame: InterruptStackFrame)
+{
+    println!("[EXCEPTION] DEBUG\n{:#?}", stack_frame);
+}
+
+/**
+ *  Non-Maskable Interrupt (NMI) handler.
+ *
+ *  Triggered by hardware errors or watchdogs, ignoring RFLAGS.IF.
+ */
+pub extern "x86-interrupt" fn non_maskable_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    println!("[EXCEPTION] NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
+
+/**
+ *  Breakpoint (#BP) exception handler.
  *
  *  Triggered by execution of an INT3 instruction.
  */
@@ -21,42 +76,428 @@ pub extern "x86-interrupt" fn breakpoint_handler(
     println!("[EXCEPTION] BREAKPOINT\n{:#?}", stack_frame);
 }
 
+/**
+ *  Overflow (#OF) exception handler.
+ *
+ *  Triggered by an INTO instruction when RFLAGS.OF is set
+ *  (INTO is invalid in 64-bit mode, so only reachable by `int 4`).
+ */
+pub extern "x86-interrupt" fn overflow_handler(
+    stack_frame: InterruptStackFrame)
+{
+    println!("[EXCEPTION] OVERFLOW\n{:#?}", stack_frame);
+}
+
+/**
+ *  Bound Range Exceeded (#BR) exception handler.
+ *
+ *  Triggered by a BOUND instruction with an out of bounds index
+ *  (BOUND is invalid in 64-bit mode, so only reachable by `int 5`).
+ */
+extern "C" fn bound_range_exceeded_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] BOUND RANGE EXCEEDED");
+    fault(context);
+}
+
+exception_entry!(bound_range_exceeded_entry, bound_range_exceeded_handler,
+    HandlerFunc, no_error_code);
+
+/**
+ *  Invalid Opcode (#UD) exception handler.
+ *
+ *  Triggered by reserved or undefined opcodes (e.g. UD2),
+ *  or by instructions unsupported by the CPU.
+ */
+extern "C" fn invalid_opcode_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] INVALID OPCODE");
+    fault(context);
+}
+
+exception_entry!(invalid_opcode_entry, invalid_opcode_handler,
+    HandlerFunc, no_error_code);
+
+/**
+ *  Device Not Available (#NM) exception handler.
+ *
+ *  Triggered by an x87/SSE instruction whilst CR0.TS or CR0.EM is set.
+ *  As there's no lazy FPU context switching, it's always fatal.
+ */
+extern "C" fn device_not_available_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] DEVICE NOT AVAILABLE");
+    fault(context);
+}
+
+exception_entry!(device_not_available_entry, device_not_available_handler,
+    HandlerFunc, no_error_code);
+
 /**
  *  Double Fault exception handler.
  *
  *  Triggered when an exception occurs whilst
- *  handling a previous exception.
+ *  handling a previous exception. Mostly caused by stack overflows,
+ *  as the page fault on the guard page can't push its frame; these
+ *  are recognized by the accessed address (still in CR2).
  */
-pub extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
-{
+extern "C" fn double_fault_handler(context: &mut Context) -> ! {
+    use x86_64::registers::control::Cr2;
+
     if crate::test::is_enabled() {
         serial_println!("[ok]");
         qemu::exit(qemu::ExitCode::Success);
     }
-    panic!("[EXCEPTION] DOUBLE FAULT\n{:#?}", stack_frame);
+    println!("\n[EXCEPTION] DOUBLE FAULT");
+    let overflowed = stack::overflowed(Cr2::read());
+    if let Some(stack) = overflowed {
+        println!("stack overflow on {}", stack.name);
+    }
+    dump(context);
+    match overflowed {
+        Some(stack) => panic!("stack overflow on {}", stack.name),
+        None => panic!("double fault at {:#x}", context.rip),
+    }
 }
 
+exception_entry!(double_fault_entry, double_fault_handler,
+    DivergingHandlerFuncWithErrCode);
+
+/**
+ *  Invalid TSS (#TS) exception handler.
+ *
+ *  Triggered on task switches or TSS accesses with an invalid TSS.
+ *  Error code holds the selector of the offending segment.
+ */
+extern "C" fn invalid_tss_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] INVALID TSS");
+    println!("Error Code: {:?}", SelectorErrorCode(context.error_code));
+    fault(context);
+}
+
+exception_entry!(invalid_tss_entry, invalid_tss_handler,
+    HandlerFuncWithErrCode);
+
+/**
+ *  Segment Not Present (#NP) exception handler.
+ *
+ *  Triggered when loading a segment or gate whose present bit is clear.
+ *  Error code holds the selector of the offending segment.
+ */
+extern "C" fn segment_not_present_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] SEGMENT NOT PRESENT");
+    println!("Error Code: {:?}", SelectorErrorCode(context.error_code));
+    fault(context);
+}
+
+exception_entry!(segment_not_present_entry, segment_not_present_handler,
+    HandlerFuncWithErrCode);
+
+/**
+ *  Stack Segment Fault (#SS) exception handler.
+ *
+ *  Triggered by non-canonical stack addresses or by loading a
+ *  not present stack segment (whose selector is then the error code).
+ */
+extern "C" fn stack_segment_fault_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] STACK SEGMENT FAULT");
+    println!("Error Code: {:?}", SelectorErrorCode(context.error_code));
+    fault(context);
+}
+
+exception_entry!(stack_segment_fault_entry, stack_segment_fault_handler,
+    HandlerFuncWithErrCode);
+
+/**
+ *  General Protection Fault (#GP) exception handler.
+ *
+ *  Triggered by many protection violations, such as non-canonical
+ *  addresses or invalid segment selectors (then given as error code).
+ */
+extern "C" fn general_protection_fault_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] GENERAL PROTECTION FAULT");
+    println!("Error Code: {:?}", SelectorErrorCode(context.error_code));
+    fault(context);
+}
+
+exception_entry!(general_protection_fault_entry,
+    general_protection_fault_handler, HandlerFuncWithErrCode);
+
 /**
  *  Page Fault exception handler.
  *
- *  Additionally prints fault's accessed address
- *  and specific PF error code.
+ *  Fault is first dispatched to the region registered for the
+ *  accessed address (see [`page_fault`]), returning if serviced.
+ *  Otherwise, accessed address, PF error code and reason (such as
+ *  a stack overflow, if address is in a stack's guard page) are printed.
  */
- pub extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    pf_error_code: PageFaultErrorCode)
-{
+extern "C" fn page_fault_handler(context: &mut Context) {
     use x86_64::registers::control::Cr2;
 
+    let addr = Cr2::read();
+    let pf_error_code =
+        PageFaultErrorCode::from_bits_truncate(context.error_code);
+    let (region, result) = page_fault::dispatch(addr, pf_error_code);
+    let reason = match result {
+        Ok(()) => return,
+        Err(reason) => reason,
+    };
     println!("\n[EXCEPTION] PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", addr);
     println!("Error Code: {:?}", pf_error_code);
-    panic!("{:#?}", stack_frame);
+    match (stack::overflowed(addr), region) {
+        (Some(stack), _) => println!("stack overflow on {}", stack.name),
+        (None, Some(region)) =>
+            println!("Reason: {:?} in {}", reason, region.name),
+        (None, None) => println!("Reason: {:?}", reason),
+    }
+    fault(context);
+}
+
+exception_entry!(page_fault_entry, page_fault_handler,
+    PageFaultHandlerFunc);
+
+/**
+ *  x87 Floating-Point (#MF) exception handler.
+ *
+ *  Triggered by an unmasked x87 FPU error, reported
+ *  on the next waiting x87 instruction.
+ */
+extern "C" fn x87_floating_point_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] x87 FLOATING-POINT");
+    fault(context);
+}
+
+exception_entry!(x87_floating_point_entry, x87_floating_point_handler,
+    HandlerFunc, no_error_code);
+
+/**
+ *  Alignment Check (#AC) exception handler.
+ *
+ *  Triggered by unaligned memory accesses whilst alignment checking
+ *  is enabled (only possible at CPL 3). Error code is always zero.
+ */
+extern "C" fn alignment_check_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] ALIGNMENT CHECK");
+    println!("Error Code: {:#x}", context.error_code);
+    fault(context);
+}
+
+exception_entry!(alignment_check_entry, alignment_check_handler,
+    HandlerFuncWithErrCode);
+
+/**
+ *  Machine Check (#MC) exception handler.
+ *
+ *  Triggered by internal CPU errors or bus errors. Never recoverable
+ *  here, as machine check banks aren't inspected.
+ */
+extern "C" fn machine_check_handler(context: &mut Context) -> ! {
+    println!("\n[EXCEPTION] MACHINE CHECK");
+    dump(context);
+    panic!("machine check at {:#x}", context.rip);
 }
 
+exception_entry!(machine_check_entry, machine_check_handler,
+    DivergingHandlerFunc, no_error_code);
+
+/**
+ *  SIMD Floating-Point (#XM) exception handler.
+ *
+ *  Triggered by an unmasked SSE floating-point error
+ *  (requires CR4.OSXMMEXCPT to be set).
+ */
+extern "C" fn simd_floating_point_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] SIMD FLOATING-POINT");
+    fault(context);
+}
+
+exception_entry!(simd_floating_point_entry, simd_floating_point_handler,
+    HandlerFunc, no_error_code);
+
+/**
+ *  Virtualization (#VE) exception handler.
+ *
+ *  Triggered by EPT violations in a VMX guest (if enabled by the VMM).
+ */
+extern "C" fn virtualization_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] VIRTUALIZATION");
+    fault(context);
+}
+
+exception_entry!(virtualization_entry, virtualization_handler,
+    HandlerFunc, no_error_code);
+
+/**
+ *  Security Exception (#SX) handler.
+ *
+ *  Triggered by security-sensitive events under AMD SVM.
+ *  Error code tells which event happened (e.g. 1 for INIT redirection).
+ */
+extern "C" fn security_exception_handler(context: &mut Context) {
+    println!("\n[EXCEPTION] SECURITY EXCEPTION");
+    println!("Error Code: {:#x}", context.error_code);
+    fault(context);
+}
+
+exception_entry!(security_exception_entry, security_exception_handler,
+    HandlerFuncWithErrCode);
+
 /*---------------------------------------------------------------------------*/
 
+/**
+ *  Selector error code, pushed by segment related exceptions.
+ *
+ *  | Bit position(s) | Field                                      |
+ *  |-----------------|--------------------------------------------|
+ *  | 0               | external event (e.g. hardware interrupt)   |
+ *  | 1 to 2          | descriptor table (GDT, IDT, LDT, IDT)      |
+ *  | 3 to 15         | selector index                             |
+ */
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// If exception originated externally to the program.
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    /// Name of the descriptor table referenced by the selector.
+    pub fn table(self) -> &'static str {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        }
+    }
+
+    /// Selector index in the descriptor table.
+    pub fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Debug for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SelectorErrorCode")
+            .field("raw", &format_args!("{:#x}", self.0))
+            .field("external", &self.external())
+            .field("table", &self.table())
+            .field("index", &format_args!("{:#x}", self.index()))
+            .finish()
+    }
+}
+
+/**
+ *  Finishes handling of a fault, after its name has been printed,
+ *  dumping registers and panicking.
+ *
+ *  In tests, expected faults are recovered from instead.
+ */
+fn fault(context: &mut Context) {
+    #[cfg(test)]
+    if recover(context) {
+        return;
+    }
+    dump(context);
+    panic!("fatal exception at {:#x}", context.rip);
+}
+
+/**
+ *  Prints register context and backtrace of interrupted code
+ *  on both VGA buffer and serial interface.
+ */
+fn dump(context: &Context) {
+    let backtrace = Backtrace::from_frame(context.rip, context.rbp);
+    println!("{}\n{}", context, backtrace);
+    serial_println!("{}\n{}", context, backtrace);
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Address where execution resumes after an expected fault
+ *  (zero when no fault is expected).
+ */
+#[cfg(test)]
+static RECOVERY_ADDR: AtomicU64 = AtomicU64::new(0);
+
+/// Error code of the last recovered fault.
+#[cfg(test)]
+static RECOVERED_ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+
+/**
+ *  Resumes execution at the recovery address, if fault was
+ *  expected (see [`RECOVERY_ADDR`]).
+ *
+ *  Returns `false` if it wasn't.
+ */
+#[cfg(test)]
+fn recover(context: &mut Context) -> bool {
+    let addr = RECOVERY_ADDR.swap(0, Ordering::SeqCst);
+    if addr == 0 {
+        return false;
+    }
+    println!("{}", context);
+    RECOVERED_ERROR_CODE.store(context.error_code, Ordering::SeqCst);
+    context.rip = addr;
+    true
+}
+
+/**
+ *  Runs given instructions expecting them to fault, setting
+ *  the recovery address to right after them beforehand.
+ *
+ *  Operands may follow instructions, after a semicolon.
+ */
+#[cfg(test)]
+macro_rules! expect_fault {
+    ($($instr:literal),+ $(; $($operands:tt)*)?) => {
+        unsafe {
+            asm!(
+                "lea {recovery_tmp}, [rip + 2f]",
+                "mov [{recovery_slot}], {recovery_tmp}",
+                $($instr,)+
+                "2:",
+                recovery_slot = in(reg)
+                    &RECOVERY_ADDR as *const AtomicU64 as *mut u64,
+                recovery_tmp = out(reg) _,
+                $($($operands)*)?
+            );
+        }
+        assert_eq!(RECOVERY_ADDR.load(Ordering::SeqCst), 0,
+            "expected fault didn't happen");
+    };
+}
+
+/**
+ *  Divides by zero, thus triggering a divide error exception.
+ */
+#[test_case]
+fn test_divide_error_exception() {
+    expect_fault!(
+        "xor edx, edx",
+        "div {divisor}";
+        divisor = in(reg) 0u64,
+        out("rax") _, out("rdx") _,
+    );
+}
+
+/**
+ *  Executes an INT1 instruction, thus triggering
+ *  a debug exception (which returns normally).
+ */
+#[test_case]
+fn test_debug_exception() {
+    unsafe { asm!("int 1") };
+}
+
+/**
+ *  Raises interrupt vector 2 by software, running the NMI handler.
+ */
+#[test_case]
+fn test_non_maskable_interrupt() {
+    unsafe { asm!("int 2") };
+}
+
 /**
  *  Executes an INT3 instruction, thus triggering
  *  a breakpoint exception.
@@ -65,3 +506,77 @@ pub extern "x86-interrupt" fn double_fault_handler(
  fn test_breakpoint_exception() {
      x86_64::instructions::interrupts::int3();
  }
+
+/**
+ *  Raises interrupt vector 4 by software, running the #OF handler.
+ */
+#[test_case]
+fn test_overflow_exception() {
+    unsafe { asm!("int 4") };
+}
+
+/**
+ *  Raises interrupt vector 5 by software, running the #BR handler.
+ */
+#[test_case]
+fn test_bound_range_exceeded_exception() {
+    expect_fault!("int 5");
+}
+
+/**
+ *  Executes an UD2 instruction, thus triggering
+ *  an invalid opcode exception.
+ */
+#[test_case]
+fn test_invalid_opcode_exception() {
+    expect_fault!("ud2");
+}
+
+/**
+ *  Executes an x87 instruction with CR0.TS set, thus triggering
+ *  a device not available exception.
+ */
+#[test_case]
+fn test_device_not_available_exception() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+    expect_fault!("fnop");
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED)) };
+}
+
+/**
+ *  Loads a selector beyond GDT limits into DS, thus triggering
+ *  a general protection fault with that selector as error code.
+ */
+#[test_case]
+fn test_general_protection_fault() {
+    expect_fault!(
+        "mov ds, {selector:x}";
+        selector = in(reg) 0xfff8u16,
+    );
+    let error_code =
+        SelectorErrorCode(RECOVERED_ERROR_CODE.load(Ordering::SeqCst));
+    assert!(!error_code.external());
+    assert_eq!(error_code.table(), "GDT");
+    assert_eq!(error_code.index(), 0x1fff);
+}
+
+/**
+ *  Reads from a registered guard page, thus triggering
+ *  a page fault which isn't serviced.
+ */
+#[test_case]
+fn test_guard_page_fault() {
+    use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+    let addr = VirtAddr::new(0x_5555_1000_0000);
+    let id = page_fault::register("test guard", addr, 4096,
+        PageTableFlags::PRESENT, page_fault::guard).unwrap();
+    expect_fault!(
+        "mov {tmp}, [{addr}]";
+        addr = in(reg) addr.as_u64(),
+        tmp = out(reg) _,
+    );
+    page_fault::unregister(id);
+}
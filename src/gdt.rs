@@ -1,8 +1,10 @@
 /*!
  *  Global Descriptor Table (GDT).
  *
- *  Particularly handles Task State Segment (TSS) with a Interrupt
- *  Descriptor Table (IDT) stack, which is swiched to on exceptions.
+ *  Particularly handles Task State Segment (TSS) with its Interrupt
+ *  Stack Table (IST), whose stacks are swiched to on exceptions.
+ *  IST stacks are allocated (with a guard page) by [`stack`] and kept
+ *  in a registry, which also measures their usage.
  */
 
 use x86_64::{
@@ -13,10 +15,11 @@ use x86_64::{
     instructions::{
         segmentation::set_cs, tables::load_tss
     },
-    VirtAddr,
 };
 use lazy_static::lazy_static;
 
+use crate::stack::{self, Stack};
+
 /**
  *  Inits GDT table + sets/loads segments.
  */
@@ -31,6 +34,85 @@ pub fn init() {
 /// Double Fault stack index at IST table. 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// Non-Maskable Interrupt stack index at IST table.
+pub const NMI_IST_INDEX: u16 = 1;
+
+/// Machine Check stack index at IST table.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Page Fault stack index at IST table (if `ist_page_fault` is enabled).
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+/// Debug stack index at IST table (if `ist_debug` is enabled).
+pub const DEBUG_IST_INDEX: u16 = 4;
+
+/**
+ *  Gets every allocated IST stack, ordered by IST index.
+ */
+pub fn ist_stacks() -> impl Iterator<Item = &'static IstStack> {
+    IST_STACKS.iter().flatten()
+}
+
+/**
+ *  Stack switched to on exceptions through an IST entry.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct IstStack {
+    /// Index at IST table.
+    pub index: u16,
+
+    /// Address range, also naming the stack.
+    pub stack: Stack,
+}
+
+impl IstStack {
+    /**
+     *  Measures how many bytes of the stack have ever been used.
+     *
+     *  Stack is painted with a pattern when allocated, so the
+     *  highest overwritten word, scanning from the bottom, marks it.
+     */
+    pub fn high_water_mark(&self) -> u64 {
+        let words = (self.stack.top - self.stack.bottom) / 8;
+        let bottom: *const u64 = self.stack.bottom.as_ptr();
+        let untouched = (0..words)
+            .take_while(|&i| unsafe {
+                bottom.add(i as usize).read_volatile() == STACK_PAINT
+            })
+            .count() as u64;
+        (words - untouched) * 8
+    }
+
+    /**
+     *  Gets stack's size in bytes.
+     */
+    pub fn size(&self) -> u64 {
+        self.stack.top - self.stack.bottom
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  IST stacks to be allocated: index, name, size in pages
+ *  and whether it's enabled.
+ *
+ *  Page Fault and Debug stacks are optional, since a nested fault
+ *  of the same kind restarts at the IST top, overwriting the frames
+ *  of the one being handled.
+ */
+const IST_LAYOUT: [(u16, &str, u64, bool); 5] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault", 5, true),
+    (NMI_IST_INDEX, "NMI", 2, true),
+    (MACHINE_CHECK_IST_INDEX, "machine check", 2, true),
+    (PAGE_FAULT_IST_INDEX, "page fault", 4,
+        cfg!(feature = "ist_page_fault")),
+    (DEBUG_IST_INDEX, "debug", 2, cfg!(feature = "ist_debug")),
+];
+
+/// Pattern IST stacks are painted with, for measuring their usage.
+const STACK_PAINT: u64 = 0x_57ac_57ac_57ac_57ac;
+
 lazy_static! {
     /**
      *  Global Descriptor Table (GDT);
@@ -55,18 +137,36 @@ lazy_static! {
      */
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // Creates a 20 KB stack in a static memory location.
-            // Converts pointers to `VirtAddr` and returns stack top address.
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let ptr = unsafe { &STACK };
-            let stack_start = VirtAddr::from_ptr(ptr);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        // Stacks grow downwards, so their top addresses are set
+        for ist_stack in ist_stacks() {
+            tss.interrupt_stack_table[ist_stack.index as usize] =
+                ist_stack.stack.top;
+        }
         tss
     };
+
+    /**
+     *  IST stacks registry, indexed as the IST table itself.
+     *
+     *  Stacks are painted right after being allocated.
+     */
+    static ref IST_STACKS: [Option<IstStack>; 7] = {
+        let mut ist_stacks = [None; 7];
+        for &(index, name, pages, enabled) in IST_LAYOUT.iter() {
+            if !enabled {
+                continue;
+            }
+            let stack = stack::allocate(name, pages)
+                .expect("IST stack allocation failed");
+            let words = (stack.top - stack.bottom) / 8;
+            let bottom: *mut u64 = stack.bottom.as_mut_ptr();
+            for i in 0..words {
+                unsafe { bottom.add(i as usize).write_volatile(STACK_PAINT) };
+            }
+            ist_stacks[index as usize] = Some(IstStack { index, stack });
+        }
+        ist_stacks
+    };
 }
 
 /**
@@ -82,3 +182,32 @@ struct _GDT {
     /// TSS segment selector.
     tss_selector: SegmentSelector,
 }
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Raises an NMI by software, whose handler must then run
+ *  on its own IST stack, leaving a high-water mark on it.
+ */
+#[test_case]
+fn test_nmi_stack_usage() {
+    let nmi = ist_stacks()
+        .find(|ist_stack| ist_stack.index == NMI_IST_INDEX)
+        .expect("NMI stack not allocated");
+    assert_eq!(nmi.stack.name, "NMI");
+
+    unsafe { asm!("int 2") };
+    let used = nmi.high_water_mark();
+    assert!(used > 0 && used <= nmi.size());
+}
+
+/**
+ *  Checks that double fault stack is registered, but left unused.
+ */
+#[test_case]
+fn test_double_fault_stack_unused() {
+    let double_fault = ist_stacks()
+        .find(|ist_stack| ist_stack.index == DOUBLE_FAULT_IST_INDEX)
+        .expect("double fault stack not allocated");
+    assert_eq!(double_fault.high_water_mark(), 0);
+}
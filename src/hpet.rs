@@ -0,0 +1,413 @@
+/*!
+ *  High Precision Event Timer (HPET).
+ *
+ *  HPET has a main counter, running at a fixed frequency (at least
+ *  10 MHz), and a set of timers, each raising an interrupt when the
+ *  counter reaches its comparator, either once or periodically.
+ *  Its registers, described by the ACPI HPET table, are memory mapped.
+ *
+ *  Counter and comparators may be only 32 bits wide, in which case
+ *  they wrap around every few minutes (upper halves reading zero).
+ *
+ *  Timer interrupts are either routed to an I/O APIC input or, in
+ *  legacy replacement mode, take over PIT's IRQ 0 (timer 0) and RTC's
+ *  IRQ 8 (timer 1), which works with either interrupt controller.
+ */
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::{
+    instructions::interrupts::without_interrupts, PhysAddr, VirtAddr
+};
+
+use crate::acpi::{self, GenericAddress};
+use crate::apic::{self, ApicError};
+use crate::memory::{self, MapError};
+
+/**
+ *  Maps HPET registers, as found in ACPI, and starts its main counter.
+ */
+pub fn init() -> Result<(), HpetError> {
+    let table = acpi::hpet().ok_or(HpetError::NotFound)?;
+    if table.address.address_space != GenericAddress::SYSTEM_MEMORY {
+        return Err(HpetError::NotFound);
+    }
+    let phys_addr = PhysAddr::try_new(table.address.address)
+        .map_err(|_| HpetError::NotFound)?;
+    let base = unsafe { memory::map_mmio(phys_addr, REGISTERS_SIZE)? };
+
+    let capabilities = unsafe { read(base, CAPABILITIES) };
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD {
+        return Err(HpetError::InvalidPeriod);
+    }
+    unsafe {
+        // Leaves every timer disabled before starting the counter
+        let timers = ((capabilities >> 8) & 0x1f) + 1;
+        for timer in 0..timers {
+            let config = read(base, timer_config(timer as u8));
+            write(base, timer_config(timer as u8),
+                config & !(TIMER_ENABLE | TIMER_PERIODIC));
+        }
+        let config = read(base, CONFIGURATION);
+        write(base, CONFIGURATION, config | ENABLE);
+    }
+    let counter_mask = if capabilities & COUNT_SIZE_CAPABLE != 0 {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+    PERIOD.store(period, Ordering::Relaxed);
+    COUNTER_MASK.store(counter_mask, Ordering::Relaxed);
+    BASE.store(base.as_u64(), Ordering::Release);
+    Ok(())
+}
+
+/**
+ *  If `init` has succeeded.
+ */
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/**
+ *  Reads main counter, if enabled.
+ *
+ *  Counter wraps around at `u32::MAX` if only 32 bits wide.
+ */
+pub fn counter() -> Option<u64> {
+    let base = base()?;
+    Some(unsafe { read(base, MAIN_COUNTER) } & counter_mask())
+}
+
+/**
+ *  Gets main counter's frequency in Hz, if enabled.
+ */
+pub fn frequency() -> Option<u64> {
+    base()?;
+    Some(FEMTOSECONDS_PER_SECOND / PERIOD.load(Ordering::Relaxed))
+}
+
+/**
+ *  Gets the number of timers, if enabled.
+ */
+pub fn timers() -> Option<u8> {
+    let capabilities = unsafe { read(base()?, CAPABILITIES) };
+    Some(((capabilities >> 8) & 0x1f) as u8 + 1)
+}
+
+/**
+ *  Busy-waits for `duration`, polling main counter.
+ *
+ *  Elapsed counts are summed up poll by poll, so that waits last
+ *  across counter wrap arounds.
+ */
+pub fn wait(duration: Duration) -> Result<(), HpetError> {
+    let mut last = counter().ok_or(HpetError::NotEnabled)?;
+    let counts = to_counts(duration);
+    let mask = counter_mask();
+    let mut elapsed = 0u64;
+    while elapsed < counts {
+        core::hint::spin_loop();
+        let now = counter().unwrap_or(last);
+        elapsed = elapsed.saturating_add(now.wrapping_sub(last) & mask);
+        last = now;
+    }
+    Ok(())
+}
+
+/**
+ *  Starts `timer`, raising an interrupt through `route` after `delay`,
+ *  then (if `Periodic`) every `delay` afterwards.
+ *
+ *  Returns the actual delay, rounded to counter's period. Delays
+ *  must fit in timer's comparator (32 bits wide on some timers).
+ */
+pub fn start_timer(timer: u8, mode: TimerMode, delay: Duration,
+    route: Route) -> Result<Duration, HpetError>
+{
+    let base = base().ok_or(HpetError::NotEnabled)?;
+    if timer >= timers().unwrap_or(0) {
+        return Err(HpetError::InvalidTimer(timer));
+    }
+    let counts = to_counts(delay).max(1);
+    let capabilities = unsafe { read(base, timer_config(timer)) };
+    if mode == TimerMode::Periodic && capabilities & PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::PeriodicUnsupported(timer));
+    }
+    let mask = comparator_mask(capabilities, counter_mask());
+    if counts > mask {
+        return Err(HpetError::DelayTooLong);
+    }
+
+    let mut config = capabilities
+        & !(TIMER_ENABLE | TIMER_PERIODIC | TIMER_LEVEL | ROUTE_MASK);
+    match route {
+        Route::Legacy if timer <= 1 => {}
+        Route::Legacy => return Err(HpetError::InvalidRoute),
+        Route::IoApic { gsi, vector } => {
+            if gsi >= 32 || (capabilities >> 32) & (1 << gsi) == 0 {
+                return Err(HpetError::InvalidRoute);
+            }
+            apic::route_irq(gsi, vector)?;
+            config |= (gsi as u64) << 9;
+        }
+    }
+    without_interrupts(|| unsafe {
+        let general = read(base, CONFIGURATION);
+        let general = match route {
+            Route::Legacy => general | LEGACY_REPLACEMENT,
+            Route::IoApic { .. } => general,
+        };
+        // Counter is stopped whilst setting comparator and period
+        write(base, CONFIGURATION, general & !ENABLE);
+        let comparator = read(base, MAIN_COUNTER).wrapping_add(counts) & mask;
+        match mode {
+            TimerMode::Periodic => {
+                let config = config | TIMER_PERIODIC | TIMER_VALUE_SET;
+                write(base, timer_config(timer), config);
+                write(base, timer_comparator(timer), comparator);
+                write(base, timer_comparator(timer), counts);
+            }
+            TimerMode::OneShot => {
+                write(base, timer_config(timer), config);
+                write(base, timer_comparator(timer), comparator);
+            }
+        }
+        let config = read(base, timer_config(timer));
+        write(base, timer_config(timer), config | TIMER_ENABLE);
+        write(base, CONFIGURATION, general | ENABLE);
+    });
+    let period = PERIOD.load(Ordering::Relaxed) as u128;
+    Ok(Duration::from_nanos((counts as u128 * period / 1_000_000) as u64))
+}
+
+/**
+ *  Stops `timer`, which raises no more interrupts.
+ */
+pub fn stop_timer(timer: u8) -> Result<(), HpetError> {
+    let base = base().ok_or(HpetError::NotEnabled)?;
+    if timer >= timers().unwrap_or(0) {
+        return Err(HpetError::InvalidTimer(timer));
+    }
+    unsafe {
+        let config = read(base, timer_config(timer));
+        write(base, timer_config(timer),
+            config & !(TIMER_ENABLE | TIMER_PERIODIC));
+    }
+    Ok(())
+}
+
+/**
+ *  How often a timer fires.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Once, after the delay.
+    OneShot,
+
+    /// Every delay.
+    Periodic,
+}
+
+/**
+ *  Where a timer's interrupt goes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// PIT's IRQ 0 (timer 0) or RTC's IRQ 8 (timer 1).
+    Legacy,
+
+    /// I/O APIC input `gsi`, routed to `vector`.
+    IoApic { gsi: u32, vector: u8 },
+}
+
+/**
+ *  Errors returned on HPET operations.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// No (memory mapped) HPET in ACPI tables.
+    NotFound,
+
+    /// Counter period is zero or above 100 ns, as HPET must not have.
+    InvalidPeriod,
+
+    /// `init` hasn't succeeded.
+    NotEnabled,
+
+    /// Timer doesn't exist.
+    InvalidTimer(u8),
+
+    /// Timer can't fire periodically.
+    PeriodicUnsupported(u8),
+
+    /// Timer can't be routed as requested.
+    InvalidRoute,
+
+    /// Delay doesn't fit in timer's comparator.
+    DelayTooLong,
+
+    /// Routing through the I/O APIC failed.
+    Apic(ApicError),
+
+    /// Mapping registers failed.
+    MapFailed(MapError),
+}
+
+impl From<MapError> for HpetError {
+    fn from(err: MapError) -> Self {
+        HpetError::MapFailed(err)
+    }
+}
+
+impl From<ApicError> for HpetError {
+    fn from(err: ApicError) -> Self {
+        HpetError::Apic(err)
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Size of registers' block in bytes.
+const REGISTERS_SIZE: u64 = 1024;
+
+/// Register offsets.
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+/// Capabilities bit set if main counter is 64 bits wide.
+const COUNT_SIZE_CAPABLE: u64 = 1 << 13;
+
+/// General configuration bits.
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+/// Timer configuration and capability bits.
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const ROUTE_MASK: u64 = 0x1f << 9;
+
+/// Maximum counter period allowed by specification (100 ns).
+const MAX_PERIOD: u64 = 100_000_000;
+
+/// Femtoseconds (counter period's unit) in a second.
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// Virtual base address of registers (zero if not enabled).
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Main counter period in femtoseconds.
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// Bits main counter actually has.
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/**
+ *  Gets registers' base, if enabled.
+ */
+fn base() -> Option<VirtAddr> {
+    match BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(VirtAddr::new(base)),
+    }
+}
+
+/**
+ *  Gets the mask of main counter's bits.
+ */
+fn counter_mask() -> u64 {
+    COUNTER_MASK.load(Ordering::Relaxed)
+}
+
+/**
+ *  Gets the mask of a timer's comparator bits, given its capabilities,
+ *  as it's as wide as both timer and main counter are.
+ */
+fn comparator_mask(capabilities: u64, counter_mask: u64) -> u64 {
+    if capabilities & TIMER_64BIT_CAPABLE != 0 {
+        counter_mask
+    } else {
+        counter_mask & u32::MAX as u64
+    }
+}
+
+/**
+ *  Converts a duration to main counter increments, saturating.
+ */
+fn to_counts(duration: Duration) -> u64 {
+    let period = PERIOD.load(Ordering::Relaxed).max(1) as u128;
+    u64::try_from(duration.as_nanos() * 1_000_000 / period)
+        .unwrap_or(u64::MAX)
+}
+
+/// Offset of `timer`'s configuration and capabilities register.
+fn timer_config(timer: u8) -> u64 {
+    0x100 + 0x20 * timer as u64
+}
+
+/// Offset of `timer`'s comparator register.
+fn timer_comparator(timer: u8) -> u64 {
+    0x108 + 0x20 * timer as u64
+}
+
+/**
+ *  Reads a register, given registers' base.
+ */
+unsafe fn read(base: VirtAddr, offset: u64) -> u64 {
+    let ptr: *const u64 = (base + offset).as_ptr();
+    ptr.read_volatile()
+}
+
+/**
+ *  Writes a register, given registers' base.
+ */
+unsafe fn write(base: VirtAddr, offset: u64, value: u64) {
+    let ptr: *mut u64 = (base + offset).as_mut_ptr();
+    ptr.write_volatile(value);
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Checks that QEMU's HPET counter runs at a sane frequency.
+ */
+#[test_case]
+fn test_counter() {
+    assert!(is_enabled(), "HPET not found");
+    assert!(frequency().unwrap() >= 10_000_000);
+    let start = counter().unwrap();
+    wait(Duration::from_micros(100)).unwrap();
+    assert!(counter().unwrap() > start);
+}
+
+/**
+ *  Checks timer argument validation.
+ */
+#[test_case]
+fn test_invalid_timer() {
+    let delay = Duration::from_millis(1);
+    let result = start_timer(31, TimerMode::OneShot, delay, Route::Legacy);
+    assert_eq!(result, Err(HpetError::InvalidTimer(31)));
+    let result = start_timer(2, TimerMode::OneShot, delay, Route::Legacy);
+    assert_eq!(result, Err(HpetError::InvalidRoute));
+}
+
+/**
+ *  Checks that comparators are 32 bits wide unless both timer
+ *  and main counter are 64 bits wide.
+ */
+#[test_case]
+fn test_comparator_mask() {
+    let mask_32 = u32::MAX as u64;
+    assert_eq!(comparator_mask(0, u64::MAX), mask_32);
+    assert_eq!(comparator_mask(PERIODIC_CAPABLE, u64::MAX), mask_32);
+    assert_eq!(comparator_mask(TIMER_64BIT_CAPABLE, mask_32), mask_32);
+    assert_eq!(comparator_mask(TIMER_64BIT_CAPABLE, u64::MAX), u64::MAX);
+}
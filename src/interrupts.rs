@@ -1,18 +1,37 @@
 /*!
  *  Interrupts handling.
+ *
+ *  External interrupts are taken through the local and I/O APICs when
+ *  the CPU has them, falling back to the legacy chained PICs otherwise.
+ *  PICs can also be chosen on boot, calling [`force_pic`] before
+ *  [`init`], or at build time, with the `legacy_pic` feature. Both
+ *  controllers deliver the same vectors, so handlers only differ on
+ *  their EOIs, done through [`end_of_interrupt`].
+ *
+ *  Under QEMU, the fallback can be exercised on boot by running with
+ *  `-cpu qemu64,-apic` (no APIC reported by CPUID).
  */
 
-use x86_64::instructions::port::PortGeneric;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use x86_64::structures::idt::{
     InterruptDescriptorTable, InterruptStackFrame
 };
-use x86_64::instructions::interrupts;
+use x86_64::instructions::interrupts::{self, without_interrupts};
 use pic8259::ChainedPics;
 use lazy_static::lazy_static;
 
+use crate::apic;
 use crate::gdt;
 use crate::exceptions;
+use crate::keyboard;
+use crate::mouse;
 use crate::println;
+use crate::ps2;
+use crate::rtc;
+use crate::task;
+use crate::time;
+use crate::timer;
 
 /**
  *  Loading and initialization procedures.
@@ -22,11 +41,89 @@ use crate::println;
     IDT.load();
     gdt::init();
 
-    // Initializes PICs and enable CPU listening to interrupts
-    unsafe { PICS.lock().initialize() };
+    // Sets up interrupt controller, PS/2 devices and timer,
+    // then enable CPU listening to interrupts
+    init_controller();
+    if let Err(err) = ps2::init() {
+        println!("PS/2 controller unavailable: {:?}", err);
+    } else if let Err(err) = mouse::init() {
+        println!("PS/2 mouse unavailable: {:?}", err);
+    }
+    time::init(InterruptIndex::Timer as u8);
     interrupts::enable();
 }
 
+/**
+ *  Interrupt controllers available for external interrupts.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    /// Legacy chained 8259 PICs.
+    Pic,
+
+    /// Local APIC, with I/O APIC routing IRQs.
+    Apic,
+}
+
+/**
+ *  Makes `init` use the legacy PICs, even if an APIC is present.
+ *
+ *  Has no effect once `init` has run.
+ */
+pub fn force_pic() {
+    FORCE_PIC.store(true, Ordering::Relaxed);
+}
+
+/**
+ *  Gets the controller chosen on `init`.
+ */
+pub fn controller() -> Controller {
+    if USING_APIC.load(Ordering::Acquire) {
+        Controller::Apic
+    } else {
+        Controller::Pic
+    }
+}
+
+/**
+ *  Unmasks ISA `irq` on the active controller, delivering it on
+ *  vector `irq + 32` (as remapped PICs do).
+ */
+pub fn enable_irq(irq: u8) -> Result<(), apic::ApicError> {
+    match controller() {
+        Controller::Apic => {
+            apic::route_irq(apic::isa_irq_gsi(irq), PIC_1_OFFSET + irq)
+        }
+        Controller::Pic => {
+            without_interrupts(|| {
+                let mut pics = PICS.lock();
+                let [mut mask_1, mut mask_2] = unsafe { pics.read_masks() };
+                if irq < 8 {
+                    mask_1 &= !(1 << irq);
+                } else {
+                    // Secondary PIC is cascaded through IRQ 2
+                    mask_1 &= !(1 << 2);
+                    mask_2 &= !(1 << (irq - 8));
+                }
+                unsafe { pics.write_masks(mask_1, mask_2) };
+            });
+            Ok(())
+        }
+    }
+}
+
+/**
+ *  Notifies end of interrupt for `vector` to the active controller.
+ */
+pub fn end_of_interrupt(vector: u8) {
+    match controller() {
+        Controller::Apic => apic::end_of_interrupt(),
+        Controller::Pic => unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
 /*---------------------------------------------------------------------------*/
 
 lazy_static! {
@@ -40,22 +137,78 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
 
         // Exception handlers
+        idt.divide_error
+            .set_handler_fn(exceptions::divide_error_entry());
+        let debug = idt.debug
+            .set_handler_fn(exceptions::debug_handler);
+        if cfg!(feature = "ist_debug") {
+            unsafe { debug.set_stack_index(gdt::DEBUG_IST_INDEX) };
+        }
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(exceptions::non_maskable_interrupt_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+        }
         idt.breakpoint
             .set_handler_fn(exceptions::breakpoint_handler);
+        idt.overflow
+            .set_handler_fn(exceptions::overflow_handler);
+        idt.bound_range_exceeded
+            .set_handler_fn(exceptions::bound_range_exceeded_entry());
+        idt.invalid_opcode
+            .set_handler_fn(exceptions::invalid_opcode_entry());
+        idt.device_not_available
+            .set_handler_fn(exceptions::device_not_available_entry());
         unsafe {
             idt.double_fault
-                .set_handler_fn(exceptions::double_fault_handler)
+                .set_handler_fn(exceptions::double_fault_entry())
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt.page_fault
-            .set_handler_fn(exceptions::page_fault_handler);
+        idt.invalid_tss
+            .set_handler_fn(exceptions::invalid_tss_entry());
+        idt.segment_not_present
+            .set_handler_fn(exceptions::segment_not_present_entry());
+        idt.stack_segment_fault
+            .set_handler_fn(exceptions::stack_segment_fault_entry());
+        idt.general_protection_fault
+            .set_handler_fn(exceptions::general_protection_fault_entry());
+        let page_fault = idt.page_fault
+            .set_handler_fn(exceptions::page_fault_entry());
+        if cfg!(feature = "ist_page_fault") {
+            unsafe { page_fault.set_stack_index(gdt::PAGE_FAULT_IST_INDEX) };
+        }
+        idt.x87_floating_point
+            .set_handler_fn(exceptions::x87_floating_point_entry());
+        idt.alignment_check
+            .set_handler_fn(exceptions::alignment_check_entry());
+        unsafe {
+            idt.machine_check
+                .set_handler_fn(exceptions::machine_check_entry())
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        idt.simd_floating_point
+            .set_handler_fn(exceptions::simd_floating_point_entry());
+        idt.virtualization
+            .set_handler_fn(exceptions::virtualization_entry());
+        idt.security_exception
+            .set_handler_fn(exceptions::security_exception_entry());
 
         // Normal interrupt handlers
         idt[InterruptIndex::Timer as usize]
             .set_handler_fn(timer_handler);
         idt[InterruptIndex::Keyboard as usize]
             .set_handler_fn(keyboard_handler);
-        
+        idt[InterruptIndex::Rtc as usize]
+            .set_handler_fn(rtc_handler);
+        idt[InterruptIndex::Mouse as usize]
+            .set_handler_fn(mouse_handler);
+        idt[apic::SPURIOUS_VECTOR as usize]
+            .set_handler_fn(spurious_handler);
+        idt[PIC_1_SPURIOUS_VECTOR as usize]
+            .set_handler_fn(pic_1_spurious_handler);
+        idt[PIC_2_SPURIOUS_VECTOR as usize]
+            .set_handler_fn(pic_2_spurious_handler);
+
         idt
     };
 }
@@ -66,6 +219,18 @@ const PIC_1_OFFSET: u8 = 32;
 /// Secondary PIC's vector number offset
 const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Vectors PICs raise spurious interrupts on (as their IRQ 7)
+const PIC_1_SPURIOUS_VECTOR: u8 = PIC_1_OFFSET + 7;
+const PIC_2_SPURIOUS_VECTOR: u8 = PIC_2_OFFSET + 7;
+
+/// Primary PIC's vector secondary PIC is cascaded through (IRQ 2)
+const PIC_CASCADE_VECTOR: u8 = PIC_1_OFFSET + 2;
+
+/// PICs' command ports, and command to read in-service register next
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_READ_ISR: u8 = 0x0b;
+
 /**
  *  Programmable Interrupt Controllers (PICs) in chained x86 structure.
  */
@@ -79,85 +244,221 @@ pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(
  *  PIC interrupt indices, starting by default at 32.
  */
  #[repr(u8)]
+ #[derive(Debug, Clone, Copy)]
  enum InterruptIndex {
      Timer = PIC_1_OFFSET,
      Keyboard,
+     Rtc = PIC_2_OFFSET,
+     Mouse = PIC_2_OFFSET + 4,
  }
 
+impl InterruptIndex {
+    /// Legacy ISA IRQ raising this interrupt.
+    fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
+/// If APIC was chosen over PICs on `init`.
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+
+/// If PICs were chosen through `force_pic`.
+static FORCE_PIC: AtomicBool = AtomicBool::new(false);
+
+/**
+ *  Chooses the interrupt controller, preferring the APIC.
+ *
+ *  PICs are remapped beforehand even when disabled, as they can still
+ *  raise spurious interrupts, which would otherwise hit exception
+ *  vectors. If the APIC fails to initialize, PICs are used instead.
+ */
+fn init_controller() {
+    let mut pics = PICS.lock();
+    unsafe { pics.initialize() };
+    if is_pic_forced() || !apic::is_supported() {
+        return;
+    }
+    let masks = unsafe { pics.read_masks() };
+    unsafe { pics.disable() };
+    match unsafe { init_apic() } {
+        Ok(()) => USING_APIC.store(true, Ordering::Release),
+        Err(err) => {
+            println!("APIC init failed ({:?}), using PICs", err);
+            unsafe { pics.write_masks(masks[0], masks[1]) };
+        }
+    }
+}
+
+/**
+ *  If PICs were chosen on boot or build.
+ */
+fn is_pic_forced() -> bool {
+    cfg!(feature = "legacy_pic") || FORCE_PIC.load(Ordering::Relaxed)
+}
+
+/**
+ *  Enables APIC, routing timer and keyboard IRQs through it.
+ */
+unsafe fn init_apic() -> Result<(), apic::ApicError> {
+    apic::init()?;
+    for index in [InterruptIndex::Timer, InterruptIndex::Keyboard] {
+        apic::route_irq(apic::isa_irq_gsi(index.irq()), index as u8)?;
+    }
+    Ok(())
+}
+
 /*---------------------------------------------------------------------------*/
 
 /**
  *  Timer interrupt handler, called on each timer tick.
  *
- *  Prints `'.'` and notifies EOI, thus enabling Timer interrupt again.
+ *  Counts tick, fires due timers and notifies EOI,
+ *  thus enabling Timer interrupt again.
  */
 extern "x86-interrupt" fn timer_handler(
     _stack_frame: InterruptStackFrame)
 {
-    //print!(".");
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(
-            InterruptIndex::Timer as u8);
-    }
+    time::tick();
+    timer::tick();
+    end_of_interrupt(InterruptIndex::Timer as u8);
 }
 
 /**
  *  Keyboard interrupt handler, called on key presses.
  *
- *  Receives key scancode from PS/2 data port and prints
- *  respective key, decoded from [`pc_keyboard`] crate.
+ *  Receives key scancode from PS/2 data port (unless the byte waiting
+ *  there is mouse's) and just queues it to [`keyboard`], where it's
+ *  decoded when read, waking the task awaiting it (if any).
  */
 extern "x86-interrupt" fn keyboard_handler(
     _stack_frame: InterruptStackFrame)
 {
-    use x86_64::instructions::port::{Port, ReadWriteAccess};
-    use pc_keyboard::{
-        layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1
-    };
-
-    // Builds port connected to PS2 interface 
-    const PS2_DATA_PORT: u16 = 0x60;
-    static mut PORT: PortGeneric<u8, ReadWriteAccess> =
-        Port::new(PS2_DATA_PORT);
-    
-    // Defines static `Keyboard` object.
-    // Follows US layout PS/2 Set 1; Ctrl keys are ignored.
-    lazy_static! {
-        static ref KEYBOARD: spin::Mutex<
-            Keyboard<layouts::Us104Key, ScancodeSet1>
-        > =
-            spin::Mutex::new(
-                Keyboard::new(layouts::Us104Key,
-                    ScancodeSet1, HandleControl::Ignore)
-            )
-        ;
+    // Mouse's bytes are left for its own handler
+    if let Some(scancode) = ps2::read_keyboard_output() {
+        keyboard::push_scancode(scancode);
+        task::keyboard::wake();
     }
-    // Reads key scancode from port, gets key event from it and
-    // processes it afterwards, printing character or key on screen.
-    let scancode = unsafe { PORT.read() };
-    let mut keyboard = KEYBOARD.lock();
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    // Prints char normally if in ASCII printable range;
-                    // otherwise, its code is printed instead.
-                    let ascii_code = character as u8;
-                    if (0x20..=0x7e).contains(&ascii_code) {
-                        println!("{}", character);
-                    } else {
-                        println!("{:?}", character);
-                    }
-                }
-                DecodedKey::RawKey(key) => {
-                    println!("{:?}", key);
-                }
-            }
+
+    // Notifies EOI for re-enabling key presses
+    end_of_interrupt(InterruptIndex::Keyboard as u8);
+}
+
+/**
+ *  Mouse interrupt handler, on IRQ 12 once enabled by `mouse`.
+ *
+ *  Passes the byte received to [`mouse`], which assembles packets
+ *  into events, waking the task awaiting them (if any).
+ */
+extern "x86-interrupt" fn mouse_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    // IRQ may have been raised before the byte was taken by polling
+    if let Some(byte) = ps2::read_mouse_output() {
+        if mouse::handle_byte(byte) {
+            task::mouse::wake();
         }
     }
-    // Notifies EOI for re-enabling key presses
+    end_of_interrupt(InterruptIndex::Mouse as u8);
+}
+
+/**
+ *  RTC interrupt handler, on IRQ 8 once enabled by `rtc`.
+ */
+extern "x86-interrupt" fn rtc_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    rtc::handle_interrupt();
+    end_of_interrupt(InterruptIndex::Rtc as u8);
+}
+
+/**
+ *  Spurious interrupt handler, for the local APIC's spurious vector.
+ *
+ *  No EOI must be notified, as no interrupt is actually in service.
+ */
+extern "x86-interrupt" fn spurious_handler(
+    _stack_frame: InterruptStackFrame)
+{
+}
+
+/**
+ *  Primary PIC's IRQ 7 handler, raised when an IRQ goes away before
+ *  being acknowledged (even on disabled PICs).
+ *
+ *  Only if IRQ 7 is actually in service is EOI notified.
+ */
+extern "x86-interrupt" fn pic_1_spurious_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    let mut pics = PICS.lock();
+    if read_pic_isr(PIC_1_COMMAND) & (1 << 7) != 0 {
+        unsafe { pics.notify_end_of_interrupt(PIC_1_SPURIOUS_VECTOR) };
+    }
+}
+
+/**
+ *  Secondary PIC's IRQ 15 handler, raised as primary PIC's one.
+ *
+ *  If spurious, primary PIC still takes its EOI, as it took the
+ *  cascade IRQ as a real one.
+ */
+extern "x86-interrupt" fn pic_2_spurious_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    let mut pics = PICS.lock();
+    let vector = if read_pic_isr(PIC_2_COMMAND) & (1 << 7) != 0 {
+        PIC_2_SPURIOUS_VECTOR
+    } else {
+        PIC_CASCADE_VECTOR
+    };
+    unsafe { pics.notify_end_of_interrupt(vector) };
+}
+
+/**
+ *  Reads in-service register of the PIC at `command_port`,
+ *  holding a bit per IRQ being handled.
+ *
+ *  PICs must be locked meanwhile.
+ */
+fn read_pic_isr(command_port: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let mut command = Port::<u8>::new(command_port);
     unsafe {
-        PICS.lock().notify_end_of_interrupt(
-            InterruptIndex::Keyboard as u8);
+        command.write(PIC_READ_ISR);
+        command.read()
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Checks that APIC is used whenever the CPU has one
+ *  (and PICs weren't forced).
+ */
+#[test_case]
+fn test_controller() {
+    let expected = if is_pic_forced() || !apic::is_supported() {
+        Controller::Pic
+    } else {
+        Controller::Apic
+    };
+    assert_eq!(controller(), expected);
+}
+
+/**
+ *  Checks that timer and keyboard IRQs are routed to their vectors.
+ */
+#[test_case]
+fn test_apic_routing() {
+    if controller() != Controller::Apic {
+        return;
+    }
+    for index in [InterruptIndex::Timer, InterruptIndex::Keyboard] {
+        let gsi = apic::isa_irq_gsi(index.irq());
+        let entry = apic::redirection_entry(gsi).unwrap();
+        assert_eq!(entry.vector(), index as u8);
+        assert!(!entry.is_masked());
+        assert_eq!(Some(entry.destination()), apic::local_apic_id());
     }
 }
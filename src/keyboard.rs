@@ -0,0 +1,386 @@
+/*!
+ *  Keyboard input queue.
+ *
+ *  Keyboard interrupt handler only pushes raw scancodes into a
+ *  fixed-capacity [`RingBuffer`], returning right away. Scancodes are
+ *  then decoded into keys (with [`pc_keyboard`]) outside interrupt
+ *  context, by whoever reads them.
+ *
+ *  Decoding follows a [`Layout`], switchable at runtime, and keeps
+ *  track of [`Modifiers`] itself. Whilst Ctrl is held, letters are
+ *  mapped to control characters (e.g. Ctrl+C to U+0003), following
+ *  the letter's place in the layout, not the key's.
+ *
+ *  Buffer has a single producer (the handler), which never waits,
+ *  whilst readers take turns through a lock (held with interrupts
+ *  disabled), so that no scancode is read twice. When full, incoming
+ *  scancodes are dropped and counted.
+ *
+ *  Asynchronous tasks read scancodes through
+ *  [`crate::task::keyboard::ScancodeStream`] instead.
+ */
+
+use pc_keyboard::{
+    layouts, DecodeState, DecodedKey, HandleControl, KeyCode, KeyEvent,
+    KeyState, KeyboardLayout, ScancodeSet, ScancodeSet1
+};
+use x86_64::instructions::interrupts;
+
+use crate::ring_buffer::RingBuffer;
+
+/// Maximum number of scancodes waiting to be read.
+pub const QUEUE_CAPACITY: usize = 128;
+
+/**
+ *  Reads next key, halting CPU until one is pressed.
+ *
+ *  Panics if interrupts are disabled, as no key would ever come.
+ */
+pub fn read_key() -> DecodedKey {
+    assert!(interrupts::are_enabled(), "reading with interrupts disabled");
+    loop {
+        if let Some(key) = try_read_key() {
+            return key;
+        }
+        // Checking and halting can't be split by the waking interrupt
+        interrupts::disable();
+        if QUEUE.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/**
+ *  Reads next key, if any is waiting.
+ *
+ *  Scancodes not completing a key (e.g. releases) are consumed.
+ */
+pub fn try_read_key() -> Option<DecodedKey> {
+    while let Some(scancode) = pop_scancode() {
+        if let Some(key) = decode(scancode) {
+            return Some(key);
+        }
+    }
+    None
+}
+
+/**
+ *  Decodes a scancode, returning the key it completes (if any).
+ *
+ *  Scancodes must be decoded in the order they were read.
+ */
+pub fn decode(scancode: u8) -> Option<DecodedKey> {
+    DECODER.lock().decode(scancode)
+}
+
+/**
+ *  Switches keyboard layout used on decoding.
+ */
+pub fn set_layout(layout: Layout) {
+    DECODER.lock().layout = layout;
+}
+
+/**
+ *  Gets keyboard layout used on decoding.
+ */
+pub fn layout() -> Layout {
+    DECODER.lock().layout
+}
+
+/**
+ *  Enables or disables mapping Ctrl+letter to control characters
+ *  (enabled by default). When disabled, letters are left as they are.
+ */
+pub fn set_ctrl_mapping(enabled: bool) {
+    DECODER.lock().ctrl_mapping = enabled;
+}
+
+/**
+ *  Gets modifiers' state, as of the last decoded scancode.
+ */
+pub fn modifiers() -> Modifiers {
+    DECODER.lock().modifiers
+}
+
+/**
+ *  Gets the number of scancodes dropped due to a full queue.
+ */
+pub fn dropped_scancodes() -> u64 {
+    QUEUE.dropped()
+}
+
+/**
+ *  Queues a scancode. Called by the keyboard interrupt handler only.
+ */
+pub(crate) fn push_scancode(scancode: u8) {
+    // Handler is the single producer, never interrupting itself
+    unsafe { QUEUE.push(scancode) };
+}
+
+/**
+ *  Takes the oldest queued scancode, not decoding it.
+ *
+ *  Readers take turns through a lock, held with interrupts disabled
+ *  (so that a handler reading can't deadlock on interrupted code).
+ */
+pub(crate) fn pop_scancode() -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        let _reader = READER.lock();
+        // Lock makes this the single consumer
+        unsafe { QUEUE.pop() }
+    })
+}
+
+/**
+ *  Keyboard layouts, as provided by [`pc_keyboard`].
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// US 104-key (default).
+    Us104,
+
+    /// UK 105-key.
+    Uk105,
+
+    /// French AZERTY.
+    Azerty,
+
+    /// US 104-key Dvorak.
+    Dvorak104,
+
+    /// Japanese 109-key.
+    Jis109,
+}
+
+impl Layout {
+    /**
+     *  Maps a key code to a key, given modifiers' state.
+     */
+    fn map_keycode(self, code: KeyCode, modifiers: &Modifiers)
+        -> DecodedKey
+    {
+        let modifiers = pc_keyboard::Modifiers {
+            lshift: modifiers.left_shift,
+            rshift: modifiers.right_shift,
+            lctrl: modifiers.left_ctrl,
+            rctrl: modifiers.right_ctrl,
+            numlock: modifiers.num_lock,
+            capslock: modifiers.caps_lock,
+            alt_gr: modifiers.alt_gr,
+        };
+        // Ctrl is handled afterwards, as layouts map it by key position
+        let ctrl = HandleControl::Ignore;
+        match self {
+            Layout::Us104 =>
+                layouts::Us104Key::map_keycode(code, &modifiers, ctrl),
+            Layout::Uk105 =>
+                layouts::Uk105Key::map_keycode(code, &modifiers, ctrl),
+            Layout::Azerty =>
+                layouts::Azerty::map_keycode(code, &modifiers, ctrl),
+            Layout::Dvorak104 =>
+                layouts::Dvorak104Key::map_keycode(code, &modifiers, ctrl),
+            Layout::Jis109 =>
+                layouts::Jis109Key::map_keycode(code, &modifiers, ctrl),
+        }
+    }
+}
+
+/**
+ *  State of modifier keys.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    /// Left Shift is held.
+    pub left_shift: bool,
+
+    /// Right Shift is held.
+    pub right_shift: bool,
+
+    /// Left Ctrl is held.
+    pub left_ctrl: bool,
+
+    /// Right Ctrl is held.
+    pub right_ctrl: bool,
+
+    /// (Left) Alt is held.
+    pub alt: bool,
+
+    /// AltGr (right Alt) is held.
+    pub alt_gr: bool,
+
+    /// Caps Lock is on.
+    pub caps_lock: bool,
+
+    /// Num Lock is on.
+    pub num_lock: bool,
+}
+
+impl Modifiers {
+    /// If either Shift is held.
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    /// If either Ctrl is held.
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    /**
+     *  Updates state on a key event.
+     *
+     *  Returns `false` if key isn't a modifier.
+     */
+    fn update(&mut self, event: &KeyEvent) -> bool {
+        let down = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::ControlLeft => self.left_ctrl = down,
+            KeyCode::ControlRight => self.right_ctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            KeyCode::CapsLock => self.caps_lock ^= down,
+            KeyCode::NumpadLock => self.num_lock ^= down,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Decodes PS/2 Set 1 scancodes into keys.
+ */
+struct Decoder {
+    /// Scancode sequence state.
+    state: DecodeState,
+
+    /// Modifiers' state.
+    modifiers: Modifiers,
+
+    /// Layout keys are mapped with.
+    layout: Layout,
+
+    /// If Ctrl+letter is mapped to control characters.
+    ctrl_mapping: bool,
+}
+
+impl Decoder {
+    /// Creates a decoder, for US layout with Ctrl mapping and Num Lock on.
+    const fn new() -> Self {
+        Decoder {
+            state: DecodeState::Start,
+            modifiers: Modifiers {
+                left_shift: false,
+                right_shift: false,
+                left_ctrl: false,
+                right_ctrl: false,
+                alt: false,
+                alt_gr: false,
+                caps_lock: false,
+                num_lock: true,
+            },
+            layout: Layout::Us104,
+            ctrl_mapping: true,
+        }
+    }
+
+    /**
+     *  Decodes a scancode, returning the key pressed it completes
+     *  (if any). Modifiers and releases return no key.
+     */
+    fn decode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        let event = match ScancodeSet1::advance_state(&mut self.state,
+            scancode)
+        {
+            Ok(Some(event)) => event,
+            _ => return None,
+        };
+        if self.modifiers.update(&event) || event.state != KeyState::Down {
+            return None;
+        }
+        match self.layout.map_keycode(event.code, &self.modifiers) {
+            DecodedKey::Unicode(letter) if self.ctrl_mapping
+                && self.modifiers.ctrl() && letter.is_ascii_alphabetic() =>
+            {
+                // Ctrl+A is U+0001, up to Ctrl+Z as U+001A
+                let letter = letter.to_ascii_uppercase() as u8;
+                Some(DecodedKey::Unicode((letter - b'@') as char))
+            }
+            key => Some(key),
+        }
+    }
+}
+
+/// Scancodes pushed by keyboard interrupt handler.
+static QUEUE: RingBuffer<u8, QUEUE_CAPACITY> = RingBuffer::new();
+
+/// Held by the (single) reader of `QUEUE` whilst reading.
+static READER: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Decoder of scancodes read.
+static DECODER: spin::Mutex<Decoder> = spin::Mutex::new(Decoder::new());
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Queues press and release scancodes of `A`, read as a single key.
+ */
+#[test_case]
+fn test_read_key() {
+    interrupts::without_interrupts(|| {
+        push_scancode(0x1e);
+        push_scancode(0x9e);
+    });
+    assert_eq!(try_read_key(), Some(DecodedKey::Unicode('a')));
+    assert_eq!(try_read_key(), None);
+}
+
+/**
+ *  Decodes the same keys on different layouts.
+ */
+#[test_case]
+fn test_layouts() {
+    let mut decoder = Decoder::new();
+    let mut decode_q_key = |layout| {
+        decoder.layout = layout;
+        let key = decoder.decode(0x10);
+        decoder.decode(0x90);
+        key
+    };
+    assert_eq!(decode_q_key(Layout::Us104), Some(DecodedKey::Unicode('q')));
+    assert_eq!(decode_q_key(Layout::Azerty), Some(DecodedKey::Unicode('a')));
+    assert_eq!(decode_q_key(Layout::Dvorak104),
+        Some(DecodedKey::Unicode('\'')));
+}
+
+/**
+ *  Holds Shift and Ctrl, tracked as modifiers, checking that
+ *  Ctrl+letter is mapped to a control character.
+ */
+#[test_case]
+fn test_modifiers() {
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.decode(0x2a), None);
+    assert!(decoder.modifiers.shift());
+    assert_eq!(decoder.decode(0x2e), Some(DecodedKey::Unicode('C')));
+    assert_eq!(decoder.decode(0xaa), None);
+
+    // Right Ctrl is extended (E0-prefixed)
+    decoder.decode(0xe0);
+    decoder.decode(0x1d);
+    assert!(decoder.modifiers.right_ctrl && decoder.modifiers.ctrl());
+    assert_eq!(decoder.decode(0x2e), Some(DecodedKey::Unicode('\u{3}')));
+    decoder.layout = Layout::Azerty;
+    assert_eq!(decoder.decode(0x10), Some(DecodedKey::Unicode('\u{1}')));
+    decoder.ctrl_mapping = false;
+    assert_eq!(decoder.decode(0x2e), Some(DecodedKey::Unicode('c')));
+    decoder.decode(0xe0);
+    decoder.decode(0x9d);
+    assert_eq!(decoder.modifiers,
+        Modifiers { num_lock: true, ..Modifiers::default() });
+}
@@ -1,7 +1,10 @@
 #![no_std]
-#![cfg_attr(test, no_main)]     // enable `no_main` when `cargo_test`
-#![feature(abi_x86_interrupt)]  // enable use of `extern "x86-interrupt"`
+#![cfg_attr(test, no_main)]       // enable `no_main` when `cargo_test`
+#![feature(abi_x86_interrupt)]    // enable use of `extern "x86-interrupt"`
+#![feature(alloc_error_handler)]  // enable custom allocation error handler
+#![feature(asm)]                  // enable inline assembly
 #![feature(custom_test_frameworks)]
+#![feature(naked_functions)]      // enable exception entry trampolines
 #![test_runner(crate::test::runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -9,24 +12,54 @@
  *  Minimal and personal OS project based on `blog_os`. Made in Rust.
  */
 
+extern crate alloc;
+
 pub mod vga_buffer;
 pub mod serial;
 pub mod qemu;
+pub mod gdt;
+pub mod hpet;
 pub mod interrupts;
+pub mod keyboard;
 pub mod memory;
+pub mod mouse;
+pub mod page_fault;
+pub mod acpi;
+pub mod allocator;
+pub mod apic;
+pub mod backtrace;
 pub mod panic;
+pub mod pit;
+pub mod power;
+pub mod ps2;
+pub mod ring_buffer;
+pub mod rtc;
+pub mod stack;
+pub mod symbols;
+pub mod task;
 pub mod test;
+pub mod tsc;
+pub mod time;
+pub mod timer;
 
+mod context;
 mod exceptions;
-mod gdt;
 
 /**
  *  Calls OS initialization routines.
+ *
+ *  Requires `memory::init` to have been called beforehand,
+ *  as interrupt stacks are allocated from mapped memory
+ *  (and ACPI tables are read through it).
  */
 pub fn init(is_test: bool) {
     if is_test {
         test::enable();
     }
+    stack::init();
+    if let Err(err) = acpi::init() {
+        println!("ACPI tables unavailable: {:?}", err);
+    }
     interrupts::init();
 }
 
@@ -62,8 +95,10 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
  *  Entry point for `cargo test`.
  */
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    unsafe { memory::init(boot_info) };
     init(true);
+    allocator::init_heap().expect("heap initialization failed");
     test_main();
     hlt_loop();
 }
@@ -9,11 +9,17 @@
  *  Main project module.
  */
 
+extern crate alloc;
+
 use core::panic::PanicInfo;
 
+use alloc::{boxed::Box, vec::Vec};
+
 use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
 
 use moon_os::{println, panic};
+use moon_os::task::{executor::Executor, keyboard, mouse, Task};
 
 // Defines `kernel_main` as the executable entry point.
 // This guarantees the correct arguments are passed to it.
@@ -32,29 +38,76 @@ fn panic(info: &PanicInfo) -> ! {
  *  Entry point for `cargo run`.
  */
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use moon_os::memory;
+    use moon_os::{acpi, memory, allocator, gdt, ps2, rtc, time, tsc};
 
     println!("Oi!");
     println!("Hello world {} {} {} {}", 1, 2, 3, '!');
     // panic!("Some panic message");
 
+    // Memory comes first, as interrupt stacks are allocated from it
+    unsafe { memory::init(boot_info) };
     moon_os::init(false);
 
-    let offset = boot_info.physical_memory_offset;
-    let p4_page_table = unsafe { 
-        memory::get_active_p4_page_table(offset)
-    };
-    for (i, entry) in p4_page_table.iter().enumerate() {
-        if !entry.is_unused() {
-            println!("P4 Entry #{}:\n{:#?}", i, entry);
+    memory::with_p4_page_table(|p4_page_table| {
+        for (i, entry) in p4_page_table.iter().enumerate() {
+            if !entry.is_unused() {
+                println!("P4 Entry #{}:\n{:#?}", i, entry);
+            }
         }
+    });
+
+    acpi::dump();
+    println!("Timer: {:?} at {} Hz", time::tick_source(), time::frequency());
+    println!("TSC: {} Hz{}", tsc::frequency(),
+        if tsc::is_invariant() { ", invariant" } else { "" });
+    println!("Date: {} UTC", rtc::now());
+    println!("PS/2: {:?} on port 1, {:?} on port 2",
+        ps2::device(ps2::Channel::First), ps2::device(ps2::Channel::Second));
+
+    let stats = memory::frame_stats();
+    println!("Physical frames: {} total, {} used, {} free",
+        stats.total, stats.used, stats.free);
+
+    let vga_buffer_addr = VirtAddr::new(0xb8000);
+    println!("VGA buffer: {:?} -> {:?}",
+        vga_buffer_addr, memory::translate_addr(vga_buffer_addr));
+
+    allocator::init_heap().expect("heap initialization failed");
+    let heap_value = Box::new(41);
+    let vec: Vec<u32> = (0..10).collect();
+    println!("Heap value at {:p}, vec {:?}", heap_value, vec);
+    println!("{}", allocator::stats());
+
+    for ist_stack in gdt::ist_stacks() {
+        println!("IST stack #{} ({}): {} of {} bytes used",
+            ist_stack.index, ist_stack.stack.name,
+            ist_stack.high_water_mark(), ist_stack.size());
     }
 
     #[cfg(test)]
     test_main();
 
     println!("It did not crash!");
-    moon_os::hlt_loop();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(mouse::draw_pointer()));
+    executor.run();
+}
+
+/**
+ *  Returns a number, asynchronously.
+ */
+async fn async_number() -> u32 {
+    42
+}
+
+/**
+ *  Prints a number, awaiting it.
+ */
+async fn example_task() {
+    let number = async_number().await;
+    println!("Async number: {}", number);
 }
 
 /*---------------------------------------------------------------------------*/
@@ -2,11 +2,222 @@
  *  Memory paging management.
  */
 
+use bootloader::bootinfo::{BootInfo, MemoryMap, MemoryRegionType};
 use x86_64::{
-    structures::paging::PageTable,
-    VirtAddr
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate
+    },
+    instructions::interrupts::without_interrupts,
+    PhysAddr, VirtAddr
 };
 
+/**
+ *  Initializes global page table mapper and frame allocator.
+ *
+ *  Unsafe because caller must guarantee that the complete physical
+ *  memory is mapped at `boot_info.physical_memory_offset`, and that
+ *  this is called only once (so `&mut` P4 references aren't aliased).
+ */
+pub unsafe fn init(boot_info: &'static BootInfo) {
+    let offset = boot_info.physical_memory_offset;
+    let p4_page_table = get_active_p4_page_table(offset);
+    let mapper = OffsetPageTable::new(p4_page_table, VirtAddr::new(offset));
+    let frame_allocator =
+        BootInfoFrameAllocator::init(&boot_info.memory_map, offset);
+    without_interrupts(|| {
+        *MAPPER.lock() = Some(mapper);
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+        *PHYSICAL_MEMORY_OFFSET.lock() = Some(offset);
+    });
+}
+
+/**
+ *  Gets the virtual address physical address `addr` is reachable
+ *  through, in the complete physical memory mapping.
+ *
+ *  Returns `None` if memory isn't initialized.
+ */
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    let offset = without_interrupts(|| *PHYSICAL_MEMORY_OFFSET.lock())?;
+    VirtAddr::try_new(addr.as_u64() + offset).ok()
+}
+
+/**
+ *  Translates virtual address to its mapped physical address.
+ *
+ *  Returns `None` if address isn't mapped (or memory isn't initialized).
+ */
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    without_interrupts(|| {
+        MAPPER.lock().as_ref()?.translate_addr(addr)
+    })
+}
+
+/**
+ *  Maps page to a newly allocated frame, returning the latter.
+ *
+ *  Unsafe since caller must guarantee that page isn't in use.
+ */
+pub unsafe fn map_page(page: Page, flags: PageTableFlags)
+    -> Result<PhysFrame, MapError>
+{
+    with_mapper(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_frame()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        match mapper.map_to(page, frame, flags, frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(frame)
+            }
+            Err(err) => {
+                frame_allocator.deallocate_frame(frame);
+                Err(err.into())
+            }
+        }
+    })
+}
+
+/**
+ *  Maps page to the given (already existing) frame.
+ *
+ *  Mostly used for memory-mapped I/O regions. Unsafe since caller
+ *  must guarantee that page isn't in use and that mapping the frame
+ *  won't cause any `&mut` aliasing.
+ */
+pub unsafe fn map_page_to(
+    page: Page, frame: PhysFrame, flags: PageTableFlags)
+    -> Result<(), MapError>
+{
+    with_mapper(|mapper, frame_allocator| {
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        Ok(())
+    })
+}
+
+/**
+ *  Maps `size` bytes of device memory, starting at physical address
+ *  `phys_addr`, into the MMIO window, returning `phys_addr`'s new
+ *  virtual address.
+ *
+ *  Pages are mapped uncached, so that every access reaches the
+ *  device. Unsafe since caller must guarantee that the physical range
+ *  belongs to a device (and thus isn't handed out as frames).
+ */
+pub unsafe fn map_mmio(phys_addr: PhysAddr, size: u64)
+    -> Result<VirtAddr, MapError>
+{
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let last_frame =
+        PhysFrame::containing_address(phys_addr + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let window_size = (frames.count() as u64) * Size4KiB::SIZE;
+
+    // Reserves window room beforehand, so that no lock is held
+    // whilst mapping pages
+    let start = without_interrupts(|| {
+        let mut next = MMIO_NEXT.lock();
+        if *next + window_size > MMIO_START + MMIO_SIZE {
+            return Err(MapError::MmioWindowFull);
+        }
+        let start = *next;
+        *next += window_size;
+        Ok(start)
+    })?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE;
+    let first_page = Page::containing_address(VirtAddr::new(start));
+    for (i, frame) in frames.enumerate() {
+        map_page_to(first_page + i as u64, frame, flags)?;
+    }
+    let offset = phys_addr - first_frame.start_address();
+    Ok(VirtAddr::new(start + offset))
+}
+
+/**
+ *  Unmaps page, returning the frame it was mapped to.
+ *
+ *  The frame isn't deallocated; see [`deallocate_frame`] for that.
+ *  Unsafe since caller must guarantee that page isn't in use anymore.
+ */
+pub unsafe fn unmap_page(page: Page) -> Result<PhysFrame, MapError> {
+    with_mapper(|mapper, _| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/**
+ *  Replaces flags of an already mapped page.
+ *
+ *  Unsafe since changing flags (e.g. removing `PRESENT` or `WRITABLE`)
+ *  can break memory safety of existing references to the page.
+ */
+pub unsafe fn update_flags(page: Page, flags: PageTableFlags)
+    -> Result<(), MapError>
+{
+    with_mapper(|mapper, _| {
+        mapper.update_flags(page, flags)?.flush();
+        Ok(())
+    })
+}
+
+/**
+ *  Allocates a physical frame from the global frame allocator.
+ */
+pub fn allocate_frame() -> Option<PhysFrame> {
+    without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+    })
+}
+
+/**
+ *  Returns a physical frame to the global frame allocator.
+ *
+ *  Unsafe since caller must guarantee that frame is no longer used.
+ */
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    without_interrupts(|| {
+        if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            frame_allocator.deallocate_frame(frame);
+        }
+    });
+}
+
+/**
+ *  Gets current usage counters of the global frame allocator.
+ */
+pub fn frame_stats() -> FrameStats {
+    without_interrupts(|| {
+        match FRAME_ALLOCATOR.lock().as_ref() {
+            Some(frame_allocator) => FrameStats {
+                total: frame_allocator.total_frames(),
+                used: frame_allocator.used_frames(),
+                free: frame_allocator.free_frames(),
+            },
+            None => FrameStats { total: 0, used: 0, free: 0 },
+        }
+    })
+}
+
+/**
+ *  Runs `f` with the active level 4 table (P4), as owned by the
+ *  global mapper.
+ *
+ *  Returns `None` if memory isn't initialized.
+ */
+pub fn with_p4_page_table<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&PageTable) -> R
+{
+    without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        Some(f(mapper.as_mut()?.level_4_table()))
+    })
+}
+
 /**
  *  Gets active level 4 table (P4) page reference.
  *
@@ -14,7 +225,7 @@ use x86_64::{
  *  _physical_ address (contained on CPU's c3 register), thus getting
  *  the respective P4 page's _virtual_ address.
  */
-pub unsafe fn get_active_p4_page_table(
+unsafe fn get_active_p4_page_table(
     physical_memory_offset: u64) -> &'static mut PageTable
 {
     use x86_64::registers::control::Cr3;
@@ -33,3 +244,327 @@ pub unsafe fn get_active_p4_page_table(
 
     p4_page_table
 }
+
+/*---------------------------------------------------------------------------*/
+
+/// Mapper for the active level 4 page table, set up on `init`.
+static MAPPER: spin::Mutex<Option<OffsetPageTable<'static>>> =
+    spin::Mutex::new(None);
+
+/// Global physical frame allocator, set up on `init`.
+static FRAME_ALLOCATOR: spin::Mutex<Option<BootInfoFrameAllocator>> =
+    spin::Mutex::new(None);
+
+/// Where the complete physical memory is mapped, set up on `init`.
+static PHYSICAL_MEMORY_OFFSET: spin::Mutex<Option<u64>> =
+    spin::Mutex::new(None);
+
+/// Starting address of the window where MMIO regions are mapped.
+const MMIO_START: u64 = 0x_6000_0000_0000;
+
+/// Size of the MMIO window (1 GiB).
+const MMIO_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Next free address of the MMIO window.
+static MMIO_NEXT: spin::Mutex<u64> = spin::Mutex::new(MMIO_START);
+
+/**
+ *  Locks mapper and frame allocator and runs `f` with both.
+ *
+ *  Interrupts are disabled meanwhile, so handlers which also
+ *  touch page tables can't deadlock on the locks.
+ */
+fn with_mapper<F, R>(f: F) -> Result<R, MapError>
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator)
+        -> Result<R, MapError>
+{
+    without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) =>
+                f(mapper, frame_allocator),
+            _ => Err(MapError::NotInitialized),
+        }
+    })
+}
+
+/**
+ *  Errors returned by mapping operations.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// `memory::init` hasn't been called yet.
+    NotInitialized,
+
+    /// No physical frame was available for page or page table.
+    FrameAllocationFailed,
+
+    /// Page is already mapped.
+    PageAlreadyMapped,
+
+    /// Page isn't mapped to any frame.
+    PageNotMapped,
+
+    /// Page belongs to an already mapped huge page.
+    ParentEntryHugePage,
+
+    /// Page table entry points to an invalid physical address.
+    InvalidFrameAddress(PhysAddr),
+
+    /// MMIO window has no room left for the region.
+    MmioWindowFull,
+}
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed =>
+                MapError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage =>
+                MapError::ParentEntryHugePage,
+            // Carries the frame being mapped, not the one in use
+            MapToError::PageAlreadyMapped(_) => MapError::PageAlreadyMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for MapError {
+    fn from(err: UnmapError) -> Self {
+        match err {
+            UnmapError::ParentEntryHugePage => MapError::ParentEntryHugePage,
+            UnmapError::PageNotMapped => MapError::PageNotMapped,
+            UnmapError::InvalidFrameAddress(addr) =>
+                MapError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+impl From<FlagUpdateError> for MapError {
+    fn from(err: FlagUpdateError) -> Self {
+        match err {
+            FlagUpdateError::PageNotMapped => MapError::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage =>
+                MapError::ParentEntryHugePage,
+        }
+    }
+}
+
+/**
+ *  Snapshot of frame allocator counters.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Number of usable frames in total.
+    pub total: u64,
+
+    /// Number of frames currently allocated.
+    pub used: u64,
+
+    /// Number of frames still available.
+    pub free: u64,
+}
+
+
+/**
+ *  Physical frame allocator built from bootloader's memory map.
+ *
+ *  Fresh frames are handed out sequentially from `Usable` regions.
+ *  Deallocated frames are kept in an intrusive free list (each free
+ *  frame stores the physical address of the next one in its first
+ *  8 bytes) and are always reused before any fresh frame.
+ */
+pub struct BootInfoFrameAllocator {
+    /// Memory map passed by the bootloader.
+    memory_map: &'static MemoryMap,
+
+    /// Virtual address offset where physical memory is mapped.
+    physical_memory_offset: u64,
+
+    /// Index of memory map region currently being allocated from.
+    region_index: usize,
+
+    /// Number of next never allocated frame.
+    next_frame_number: u64,
+
+    /// Head of deallocated frames list (if any).
+    free_list_head: Option<PhysFrame>,
+
+    /// Number of usable frames in total.
+    total_frames: u64,
+
+    /// Number of frames currently allocated.
+    used_frames: u64,
+}
+
+impl BootInfoFrameAllocator {
+    /**
+     *  Creates a frame allocator from the given memory map.
+     *
+     *  Unsafe because caller must guarantee that every frame marked as
+     *  `Usable` is really unused and that all physical memory is mapped
+     *  at `physical_memory_offset`.
+     */
+    pub unsafe fn init(
+        memory_map: &'static MemoryMap, physical_memory_offset: u64) -> Self
+    {
+        let total_frames = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.end_frame_number - r.range.start_frame_number)
+            .sum();
+        BootInfoFrameAllocator {
+            memory_map,
+            physical_memory_offset,
+            region_index: 0,
+            next_frame_number: 0,
+            free_list_head: None,
+            total_frames,
+            used_frames: 0,
+        }
+    }
+
+    /// Number of usable frames in total.
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    /// Number of frames currently allocated.
+    pub fn used_frames(&self) -> u64 {
+        self.used_frames
+    }
+
+    /// Number of frames still available for allocation.
+    pub fn free_frames(&self) -> u64 {
+        self.total_frames - self.used_frames
+    }
+
+    /**
+     *  Gets pointer to the free list link stored in given frame.
+     */
+    fn link_ptr(&self, frame: PhysFrame) -> *mut u64 {
+        let phys_addr = frame.start_address().as_u64();
+        (phys_addr + self.physical_memory_offset) as *mut u64
+    }
+
+    /**
+     *  Pops a frame from the free list, if not empty.
+     */
+    fn pop_free_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.free_list_head?;
+        let next = unsafe { self.link_ptr(frame).read() };
+
+        // Frame zero is never usable, so a null link marks list's end
+        self.free_list_head = match next {
+            0 => None,
+            addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+        };
+        Some(frame)
+    }
+
+    /**
+     *  Takes next never allocated frame from usable regions.
+     */
+    fn next_fresh_frame(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_map.get(self.region_index) {
+            let range = region.range;
+            if region.region_type == MemoryRegionType::Usable {
+                // Skips to region's start if it hasn't been reached yet
+                if self.next_frame_number < range.start_frame_number {
+                    self.next_frame_number = range.start_frame_number;
+                }
+                if self.next_frame_number < range.end_frame_number {
+                    let addr = self.next_frame_number * Size4KiB::SIZE;
+                    self.next_frame_number += 1;
+                    return Some(PhysFrame::containing_address(
+                        PhysAddr::new(addr)));
+                }
+            }
+            self.region_index += 1;
+        }
+        None
+    }
+}
+
+/**
+ *  Implements `FrameAllocator` trait to [`BootInfoFrameAllocator`].
+ *
+ *  Unsafe since implementer must guarantee that only unused frames
+ *  are returned (which holds as long as `init` requirements are met).
+ */
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    /**
+     *  Allocates a frame, reusing deallocated ones first.
+     */
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.pop_free_frame()
+            .or_else(|| self.next_fresh_frame())?;
+        self.used_frames += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /**
+     *  Pushes frame on top of the free list.
+     *
+     *  Caller must guarantee that frame is no longer used
+     *  and that it was allocated by this same allocator.
+     */
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = match self.free_list_head {
+            Some(head) => head.start_address().as_u64(),
+            None => 0,
+        };
+        self.link_ptr(frame).write(next);
+        self.free_list_head = Some(frame);
+        self.used_frames -= 1;
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Unused virtual address for mapping tests.
+#[cfg(test)]
+const TEST_PAGE_ADDR: u64 = 0xdead_beef_0000;
+
+/**
+ *  Checks that VGA buffer is identity mapped by the bootloader.
+ */
+#[test_case]
+fn test_translate_vga_buffer() {
+    let addr = translate_addr(VirtAddr::new(0xb8000));
+    assert_eq!(addr, Some(PhysAddr::new(0xb8000)));
+}
+
+/**
+ *  Maps a fresh page, writes to it, changes its flags
+ *  and finally unmaps it, checking results on each step.
+ */
+#[test_case]
+fn test_map_update_unmap() {
+    let page = Page::containing_address(VirtAddr::new(TEST_PAGE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let frame = unsafe { map_page(page, flags) }.unwrap();
+    let phys_addr = translate_addr(page.start_address());
+    assert_eq!(phys_addr, Some(frame.start_address()));
+
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(0x_f021_f077_f065_f04e);
+        assert_eq!(ptr.read_volatile(), 0x_f021_f077_f065_f04e);
+    }
+    let result = unsafe { map_page(page, flags) };
+    assert_eq!(result, Err(MapError::PageAlreadyMapped));
+    assert_eq!(translate_addr(page.start_address()),
+        Some(frame.start_address()));
+
+    unsafe {
+        update_flags(page, PageTableFlags::PRESENT).unwrap();
+        assert_eq!(unmap_page(page), Ok(frame));
+        assert_eq!(unmap_page(page), Err(MapError::PageNotMapped));
+        deallocate_frame(frame);
+    }
+    assert_eq!(translate_addr(page.start_address()), None);
+}
@@ -0,0 +1,360 @@
+/*!
+ *  PS/2 mouse, on the controller's second port.
+ *
+ *  Once data reporting is enabled, mouse sends a packet on each change,
+ *  one byte per IRQ 12: buttons and sign bits, then X and Y movement,
+ *  then (on IntelliMouse) wheel movement and extra buttons. Interrupt
+ *  handler assembles packets, publishing movement and button changes
+ *  as [`MouseEvent`]s into a lock-free queue, read outside interrupt
+ *  context (or through [`crate::task::mouse::MouseEventStream`]).
+ */
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::enable_irq;
+use crate::ps2::{self, Channel, Device, Ps2Error};
+use crate::ring_buffer::RingBuffer;
+
+/// ISA IRQ raised by mouse.
+pub const IRQ: u8 = 12;
+
+/// Maximum number of events waiting to be read.
+pub const QUEUE_CAPACITY: usize = 256;
+
+/**
+ *  Enables the mouse found by `ps2::init`, with its wheel and extra
+ *  buttons if it has them, and unmasks IRQ 12.
+ *
+ *  Must be run with interrupts disabled, as for `ps2::init`.
+ */
+pub fn init() -> Result<(), MouseError> {
+    match ps2::device(Channel::Second) {
+        Ok(Device::Mouse(_)) => {}
+        _ => return Err(MouseError::NoMouse),
+    }
+    // IntelliMouse reports ID 3 (with wheel) after a magic sequence
+    // of sample rates, then ID 4 (with 5 buttons) after another one
+    let mut id = enable_extension(&WHEEL_RATES)?;
+    if id == WHEEL_ID {
+        id = enable_extension(&BUTTONS_RATES)?;
+    }
+    ps2::send(Channel::Second,
+        &[SET_SAMPLE_RATE, SAMPLE_RATE, ENABLE_REPORTING])?;
+    ID.store(id, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+    enable_irq(IRQ).map_err(|_| MouseError::IrqUnavailable)
+}
+
+/**
+ *  Gets mouse ID (0 if standard, 3 if with wheel, 4 if also with
+ *  5 buttons), if enabled.
+ */
+pub fn id() -> Option<u8> {
+    if ENABLED.load(Ordering::Acquire) {
+        Some(ID.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/**
+ *  Reads next event, halting CPU until one comes.
+ *
+ *  Panics if interrupts are disabled, as no event would ever come.
+ */
+pub fn read_event() -> MouseEvent {
+    assert!(interrupts::are_enabled(), "reading with interrupts disabled");
+    loop {
+        if let Some(event) = try_read_event() {
+            return event;
+        }
+        // Checking and halting can't be split by the waking interrupt
+        interrupts::disable();
+        if EVENTS.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/**
+ *  Reads next event, if any is waiting.
+ *
+ *  Readers take turns through a lock, held with interrupts disabled
+ *  (so that a handler reading can't deadlock on interrupted code).
+ */
+pub fn try_read_event() -> Option<MouseEvent> {
+    interrupts::without_interrupts(|| {
+        let _reader = READER.lock();
+        // Lock makes this the single consumer
+        unsafe { EVENTS.pop() }
+    })
+}
+
+/**
+ *  Gets the number of events dropped due to a full queue.
+ */
+pub fn dropped_events() -> u64 {
+    EVENTS.dropped()
+}
+
+/**
+ *  Takes a byte received from mouse, publishing the events of the
+ *  packet it completes. Called by the mouse interrupt handler only.
+ *
+ *  Returns if any event was published.
+ */
+pub(crate) fn handle_byte(byte: u8) -> bool {
+    let id = ID.load(Ordering::Relaxed);
+    let mut published = false;
+    PACKET.lock().add_byte(byte, id, |event| {
+        published |= unsafe { EVENTS.push(event) };
+    });
+    published
+}
+
+/**
+ *  Mouse events.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// Movement in mouse counts, with `dy` growing upwards.
+    Move { dx: i16, dy: i16 },
+
+    /// Button pressed or released.
+    Button { button: MouseButton, pressed: bool },
+
+    /// Wheel movement in notches, positive towards the user.
+    Scroll(i8),
+}
+
+/**
+ *  Mouse buttons.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    /// Primary (left) button.
+    Left,
+
+    /// Secondary (right) button.
+    Right,
+
+    /// Middle button (or wheel click).
+    Middle,
+
+    /// Fourth button, usually "back" (if extra buttons are enabled).
+    Fourth,
+
+    /// Fifth button, usually "forward" (if extra buttons are enabled).
+    Fifth,
+}
+
+/**
+ *  Errors returned on mouse initialization.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError {
+    /// No mouse on second port.
+    NoMouse,
+
+    /// Mouse didn't take a command.
+    Ps2(Ps2Error),
+
+    /// IRQ 12 couldn't be unmasked.
+    IrqUnavailable,
+}
+
+impl From<Ps2Error> for MouseError {
+    fn from(err: Ps2Error) -> Self {
+        MouseError::Ps2(err)
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Mouse commands.
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const GET_ID: u8 = 0xf2;
+const ENABLE_REPORTING: u8 = 0xf4;
+
+/// Sample rates enabling wheel, then extra buttons.
+const WHEEL_RATES: [u8; 3] = [200, 100, 80];
+const BUTTONS_RATES: [u8; 3] = [200, 200, 80];
+
+/// ID reported once wheel is enabled.
+const WHEEL_ID: u8 = 3;
+
+/// ID reported once extra buttons are enabled.
+const BUTTONS_ID: u8 = 4;
+
+/// Packets per second.
+const SAMPLE_RATE: u8 = 100;
+
+/// First packet byte bits.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Buttons, by their bit in button state.
+const BUTTONS: [MouseButton; 5] = [
+    MouseButton::Left,
+    MouseButton::Right,
+    MouseButton::Middle,
+    MouseButton::Fourth,
+    MouseButton::Fifth,
+];
+
+/// If `init` has succeeded.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Mouse ID, telling packet format.
+static ID: AtomicU8 = AtomicU8::new(0);
+
+/// Events published by mouse interrupt handler.
+static EVENTS: RingBuffer<MouseEvent, QUEUE_CAPACITY> = RingBuffer::new();
+
+/// Held by the (single) reader of `EVENTS` whilst reading.
+static READER: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Packet being received (by mouse interrupt handler only).
+static PACKET: spin::Mutex<Packet> = spin::Mutex::new(Packet {
+    bytes: [0; 4],
+    len: 0,
+    buttons: 0,
+});
+
+/**
+ *  Sends an extension's sample rate sequence, returning the mouse
+ *  ID reported afterwards.
+ */
+fn enable_extension(rates: &[u8; 3]) -> Result<u8, Ps2Error> {
+    for &rate in rates {
+        ps2::send(Channel::Second, &[SET_SAMPLE_RATE, rate])?;
+    }
+    ps2::send(Channel::Second, &[GET_ID])?;
+    ps2::read_response()
+}
+
+/**
+ *  Packet assembly state.
+ */
+struct Packet {
+    /// Bytes received so far.
+    bytes: [u8; 4],
+
+    /// Number of bytes received.
+    len: usize,
+
+    /// State of buttons on last packet, one bit each.
+    buttons: u8,
+}
+
+impl Packet {
+    /**
+     *  Adds a byte to a packet, in the format of mouse `id`, passing
+     *  its events to `publish` once complete.
+     */
+    fn add_byte(&mut self, byte: u8, id: u8,
+        mut publish: impl FnMut(MouseEvent))
+    {
+        let size = if id == 0 { 3 } else { 4 };
+        // Bytes are dropped until a packet start, if out of sync
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < size {
+            return;
+        }
+        self.len = 0;
+
+        let flags = self.bytes[0];
+        let mut buttons = flags & 0b111;
+        let mut scroll = 0;
+        if size == 4 {
+            let extra = self.bytes[3];
+            if id == BUTTONS_ID {
+                // Low nibble is a signed 4-bit value
+                scroll = ((extra << 4) as i8) >> 4;
+                buttons |= (extra >> 1) & 0b11000;
+            } else {
+                scroll = extra as i8;
+            }
+        }
+
+        // Movement is a 9-bit value, unreliable on overflow
+        if flags & (X_OVERFLOW | Y_OVERFLOW) == 0 {
+            let dx = self.bytes[1] as i16 - ((flags & X_SIGN) as i16) * 16;
+            let dy = self.bytes[2] as i16 - ((flags & Y_SIGN) as i16) * 8;
+            if dx != 0 || dy != 0 {
+                publish(MouseEvent::Move { dx, dy });
+            }
+        }
+        for (bit, &button) in BUTTONS.iter().enumerate() {
+            let mask = 1 << bit;
+            if (buttons ^ self.buttons) & mask != 0 {
+                let pressed = buttons & mask != 0;
+                publish(MouseEvent::Button { button, pressed });
+            }
+        }
+        self.buttons = buttons;
+        if scroll != 0 {
+            publish(MouseEvent::Scroll(scroll));
+        }
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Assembles packets, checking their events.
+ */
+#[test_case]
+fn test_packets() {
+    use alloc::vec::Vec;
+
+    let mut packet = Packet { bytes: [0; 4], len: 0, buttons: 0 };
+    let mut events = Vec::new();
+
+    // Stray byte, then left press moving right by 5 and down by 3
+    for &byte in &[0x00, ALWAYS_ONE | Y_SIGN | 1, 5, 0xfd] {
+        packet.add_byte(byte, 0, |event| events.push(event));
+    }
+    assert_eq!(events, [
+        MouseEvent::Move { dx: 5, dy: -3 },
+        MouseEvent::Button { button: MouseButton::Left, pressed: true },
+    ]);
+
+    // Left release, overflowing movement being ignored
+    events.clear();
+    for &byte in &[ALWAYS_ONE | X_OVERFLOW, 0xff, 0] {
+        packet.add_byte(byte, 0, |event| events.push(event));
+    }
+    assert_eq!(events,
+        [MouseEvent::Button { button: MouseButton::Left, pressed: false }]);
+
+    // Fourth button press, scrolling up, on a 5-button mouse
+    events.clear();
+    for &byte in &[ALWAYS_ONE, 0, 0, 0x1f] {
+        packet.add_byte(byte, BUTTONS_ID, |event| events.push(event));
+    }
+    assert_eq!(events, [
+        MouseEvent::Button { button: MouseButton::Fourth, pressed: true },
+        MouseEvent::Scroll(-1),
+    ]);
+}
+
+/**
+ *  Checks that QEMU's mouse is enabled, with its wheel.
+ */
+#[test_case]
+fn test_init() {
+    let id = id().expect("mouse not enabled");
+    assert!(id == WHEEL_ID || id == BUTTONS_ID, "mouse ID {}", id);
+}
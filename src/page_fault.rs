@@ -0,0 +1,314 @@
+/*!
+ *  Page fault dispatching.
+ *
+ *  Subsystems register virtual address ranges along with a handler,
+ *  which services faults on not present pages inside them (e.g. by
+ *  mapping a frame), so that the faulting access can be retried.
+ *  Only faults outside any region, protection violations and faults
+ *  refused by the handler end up being fatal.
+ */
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr
+};
+
+use crate::memory::{self, MapError};
+
+/// Maximum number of regions registered at once.
+pub const MAX_REGIONS: usize = 32;
+
+/**
+ *  Services a fault on a not present `page` of `region`.
+ */
+pub type FaultHandler = fn(region: &Region, page: Page)
+    -> Result<(), FaultError>;
+
+/**
+ *  Registers a region covering `size` bytes from `start`,
+ *  whose faults are serviced by `handler`.
+ *
+ *  Pages mapped on faults (if any) are given `flags`.
+ *  Returns an id for unregistering it afterwards.
+ */
+pub fn register(
+    name: &'static str, start: VirtAddr, size: u64,
+    flags: PageTableFlags, handler: FaultHandler)
+    -> Result<RegionId, RegisterError>
+{
+    let end = start.as_u64().checked_add(size)
+        .filter(|_| size > 0)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(RegisterError::InvalidRange)?;
+    let region = Region { name, start, end, flags, handler };
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if regions.slots.iter().flatten()
+            .any(|other| other.overlaps(&region))
+        {
+            return Err(RegisterError::Overlapping);
+        }
+        let index = regions.slots.iter()
+            .position(|slot| slot.is_none())
+            .ok_or(RegisterError::TooManyRegions)?;
+        regions.slots[index] = Some(region);
+        Ok(RegionId { index, generation: regions.generations[index] })
+    })
+}
+
+/**
+ *  Unregisters a region, returning it.
+ *
+ *  Returns `None` if it had already been unregistered.
+ *  Pages already mapped inside it are kept as they are.
+ */
+pub fn unregister(id: RegionId) -> Option<Region> {
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if regions.generations[id.index] != id.generation {
+            return None;
+        }
+        let region = regions.slots[id.index].take()?;
+        let generation = &mut regions.generations[id.index];
+        *generation = generation.wrapping_add(1);
+        Some(region)
+    })
+}
+
+/**
+ *  Finds the region containing given address.
+ */
+pub fn region_containing(addr: VirtAddr) -> Option<Region> {
+    without_interrupts(|| {
+        REGIONS.lock().slots.iter()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .copied()
+    })
+}
+
+/**
+ *  Dispatches a page fault on `addr` to its region's handler.
+ *
+ *  Returns the region it happened in, if any, along with the
+ *  outcome. On success, the faulting access can be retried.
+ */
+pub fn dispatch(addr: VirtAddr, error_code: PageFaultErrorCode)
+    -> (Option<Region>, Result<(), FaultError>)
+{
+    let region = match region_containing(addr) {
+        Some(region) => region,
+        None => return (None, Err(FaultError::NotRegistered)),
+    };
+    let result = if
+        error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        Err(FaultError::ProtectionViolation)
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        Err(FaultError::ProtectionViolation)
+    } else {
+        (region.handler)(&region, Page::containing_address(addr))
+    };
+    (Some(region), result)
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Handler for lazily allocated memory (e.g. growable stacks),
+ *  mapping a zeroed frame to the page.
+ *
+ *  Page is writable whilst being zeroed, only then getting
+ *  region's flags (if these don't include `WRITABLE`).
+ */
+pub fn zero_fill(region: &Region, page: Page) -> Result<(), FaultError> {
+    let flags = region.flags | PageTableFlags::PRESENT;
+    unsafe {
+        memory::map_page(page, flags | PageTableFlags::WRITABLE)?;
+        let ptr: *mut u8 = page.start_address().as_mut_ptr();
+        ptr.write_bytes(0, Page::<Size4KiB>::SIZE as usize);
+        if !flags.contains(PageTableFlags::WRITABLE) {
+            memory::update_flags(page, flags)?;
+        }
+    }
+    Ok(())
+}
+
+/**
+ *  Handler for guard pages, which must never be touched.
+ */
+pub fn guard(_region: &Region, _page: Page) -> Result<(), FaultError> {
+    Err(FaultError::GuardPage)
+}
+
+/**
+ *  Registered virtual address range.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    /// Name identifying region in messages.
+    pub name: &'static str,
+
+    /// Starting address.
+    pub start: VirtAddr,
+
+    /// Ending (exclusive) address.
+    pub end: VirtAddr,
+
+    /// Flags given to pages mapped on faults.
+    pub flags: PageTableFlags,
+
+    /// Services faults inside region.
+    pub handler: FaultHandler,
+}
+
+impl Region {
+    /// If region contains given address.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// If regions share any address.
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/**
+ *  Identifies a registered region.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionId {
+    /// Index of region's slot.
+    index: usize,
+
+    /// Distinguishes regions reusing the same slot.
+    generation: u32,
+}
+
+/**
+ *  Errors returned on region registering.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// Region is empty or ends beyond canonical addresses.
+    InvalidRange,
+
+    /// Region overlaps an already registered one.
+    Overlapping,
+
+    /// All `MAX_REGIONS` slots are taken.
+    TooManyRegions,
+}
+
+/**
+ *  Reasons for a page fault not being serviced.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// Address is outside every registered region.
+    NotRegistered,
+
+    /// Page is present, or access isn't allowed by region's flags.
+    ProtectionViolation,
+
+    /// Page is a guard page.
+    GuardPage,
+
+    /// Mapping a frame to the page failed.
+    MapFailed(MapError),
+}
+
+impl From<MapError> for FaultError {
+    fn from(err: MapError) -> Self {
+        FaultError::MapFailed(err)
+    }
+}
+
+/**
+ *  Region slots.
+ */
+struct Regions {
+    /// Registered regions, in no particular order.
+    slots: [Option<Region>; MAX_REGIONS],
+
+    /// Generation of each slot, bumped when it's freed.
+    generations: [u32; MAX_REGIONS],
+}
+
+/// Registered regions.
+static REGIONS: spin::Mutex<Regions> = spin::Mutex::new(Regions {
+    slots: [None; MAX_REGIONS],
+    generations: [0; MAX_REGIONS],
+});
+
+/*---------------------------------------------------------------------------*/
+
+/// Unused virtual address for dispatching tests.
+#[cfg(test)]
+const TEST_REGION_ADDR: u64 = 0x_5555_0000_0000;
+
+/**
+ *  Touches a lazily allocated region, which must be zero filled
+ *  on first access and mapped afterwards.
+ */
+#[test_case]
+fn test_zero_fill_region() {
+    let start = VirtAddr::new(TEST_REGION_ADDR);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let id = register("test", start, 2 * 4096, flags, zero_fill).unwrap();
+
+    let ptr: *mut u64 = (start + 4096u64 + 8u64).as_mut_ptr();
+    assert_eq!(memory::translate_addr(start + 4096u64), None);
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(memory::translate_addr(start + 4096u64).is_some());
+    assert_eq!(memory::translate_addr(start), None);
+
+    assert!(unregister(id).is_some());
+    unsafe {
+        let page = Page::containing_address(start + 4096u64);
+        let frame = memory::unmap_page(page).unwrap();
+        memory::deallocate_frame(frame);
+    }
+}
+
+/**
+ *  Checks that overlapping regions are refused.
+ */
+#[test_case]
+fn test_overlapping_regions() {
+    let start = VirtAddr::new(TEST_REGION_ADDR);
+    let flags = PageTableFlags::PRESENT;
+    let id = register("test", start, 4096, flags, guard).unwrap();
+    let result = register("other", start + 4095u64, 4096, flags, guard);
+    assert_eq!(result, Err(RegisterError::Overlapping));
+    assert!(unregister(id).is_some());
+}
+
+/**
+ *  Unregisters a region twice, then with its id once its slot is
+ *  reused, checking that stale ids are refused.
+ */
+#[test_case]
+fn test_stale_region_id() {
+    let start = VirtAddr::new(TEST_REGION_ADDR);
+    let flags = PageTableFlags::PRESENT;
+    let id = register("test", start, 4096, flags, guard).unwrap();
+    assert!(unregister(id).is_some());
+    assert!(unregister(id).is_none());
+
+    let other = register("other", start, 4096, flags, guard).unwrap();
+    assert_ne!(other, id);
+    assert!(unregister(id).is_none());
+    assert_eq!(region_containing(start).map(|region| region.name),
+        Some("other"));
+    assert!(unregister(other).is_some());
+}
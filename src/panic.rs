@@ -4,24 +4,27 @@
 
 use core::panic::PanicInfo;
 
+use crate::backtrace::Backtrace;
 use crate::println;
 use crate::serial_println;
 use crate::qemu;
 
 /**
- *  Prints panic message to VGA buffer.
+ *  Prints panic message and backtrace to VGA buffer.
  */
 pub fn handler(info: &PanicInfo) -> ! {
     println!("{}", info);
+    println!("{}", Backtrace::capture());
     crate::hlt_loop();
 }
 
 /**
- *  Prints panic message to host console
+ *  Prints panic message and backtrace to host console
  *  and exits QEMU with failed exit code.
  */
 pub fn test_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]");
     serial_println!("Error: {}", info);
+    serial_println!("{}", Backtrace::capture());
     qemu::exit(qemu::ExitCode::Failed);
 }
@@ -0,0 +1,107 @@
+/*!
+ *  Programmable Interval Timer (8253/8254 PIT).
+ *
+ *  Its oscillator runs at about 1.193182 MHz, divided on each channel
+ *  by a 16-bit reload value. Channel 0 raises IRQ 0 periodically,
+ *  whilst channel 2 (gated through the PC speaker port) is polled for
+ *  waiting fixed amounts of time, e.g. when calibrating other timers.
+ */
+
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of PIT's oscillator in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/**
+ *  Programs channel 0 to raise IRQ 0 at (about) `hz` times a second.
+ *
+ *  Returns the actual frequency, as close as the divisor allows.
+ */
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = (BASE_FREQUENCY / hz.max(1)).clamp(1, 0x1_0000);
+    let mut pit = PIT.lock();
+    unsafe {
+        // Channel 0, low byte then high byte, mode 2 (rate generator)
+        pit.command.write(0x34);
+        pit.channel_0.write(divisor as u8);
+        pit.channel_0.write((divisor >> 8) as u8);
+    }
+    BASE_FREQUENCY / divisor
+}
+
+/**
+ *  Busy-waits for `duration`, polling channel 2.
+ *
+ *  Works with interrupts disabled, as no IRQ is involved.
+ */
+pub fn wait(duration: Duration) {
+    let mut counts =
+        duration.as_nanos() * BASE_FREQUENCY as u128 / 1_000_000_000;
+    let mut pit = PIT.lock();
+    while counts > 0 {
+        let chunk = counts.min(0xffff) as u16;
+        unsafe { pit.wait_counts(chunk) };
+        counts -= chunk as u128;
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  PIT's I/O ports.
+ */
+struct Pit {
+    /// Channel 0 data port.
+    channel_0: Port<u8>,
+
+    /// Channel 2 data port.
+    channel_2: Port<u8>,
+
+    /// Mode/command register.
+    command: Port<u8>,
+
+    /// PC speaker port, holding channel 2's gate and output.
+    speaker: Port<u8>,
+}
+
+impl Pit {
+    /// Channel 2 gate bit of speaker port.
+    const GATE: u8 = 1 << 0;
+
+    /// Speaker enable bit of speaker port.
+    const SPEAKER: u8 = 1 << 1;
+
+    /// Channel 2 output bit of speaker port.
+    const OUTPUT: u8 = 1 << 5;
+
+    /**
+     *  Counts down `counts` on channel 2 (mode 0, interrupt on
+     *  terminal count), waiting for its output to go high.
+     */
+    unsafe fn wait_counts(&mut self, counts: u16) {
+        // Disables speaker and gate, so that counting starts on reload
+        let speaker = self.speaker.read() & !(Self::SPEAKER | Self::GATE);
+        self.speaker.write(speaker);
+
+        // Channel 2, low byte then high byte, mode 0
+        self.command.write(0xb0);
+        self.channel_2.write(counts as u8);
+        self.channel_2.write((counts >> 8) as u8);
+
+        self.speaker.write(speaker | Self::GATE);
+        while self.speaker.read() & Self::OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        self.speaker.write(speaker);
+    }
+}
+
+/// PIT, locked so that programming sequences aren't interleaved.
+static PIT: spin::Mutex<Pit> = spin::Mutex::new(Pit {
+    channel_0: Port::new(0x40),
+    channel_2: Port::new(0x42),
+    command: Port::new(0x43),
+    speaker: Port::new(0x61),
+});
@@ -0,0 +1,317 @@
+/*!
+ *  System power-off and reboot.
+ *
+ *  Power-off puts the system into ACPI sleep state S5, writing the
+ *  `SLP_TYPx` values found in the DSDT's `\_S5` package to the FADT's
+ *  PM1 control blocks. Reboot tries, in order, the FADT reset
+ *  register, the 8042 keyboard controller's reset line and, as a last
+ *  resort, a triple fault.
+ *
+ *  Unlike `qemu::exit`, which needs QEMU's `isa-debug-exit` device
+ *  (and is thus kept for tests), these work on any ACPI machine.
+ */
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    instructions::{self, interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr
+};
+
+use crate::acpi::{self, GenericAddress};
+use crate::memory;
+use crate::println;
+
+/**
+ *  Powers system off, halting forever if that fails.
+ */
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    if let Err(err) = acpi_shutdown() {
+        println!("ACPI power-off failed ({:?}), halting", err);
+    }
+    halt()
+}
+
+/**
+ *  Reboots system, falling back to further methods on failure.
+ */
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Err(err) = acpi_reset() {
+        println!("ACPI reset failed ({:?}), trying 8042", err);
+    }
+    keyboard_controller_reset();
+    triple_fault()
+}
+
+/**
+ *  Reasons for an ACPI power operation not being done.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// FADT wasn't found.
+    NoFadt,
+
+    /// DSDT holds no `\_S5` package.
+    NoSleepType,
+
+    /// Firmware didn't switch into ACPI mode.
+    AcpiModeTimeout,
+
+    /// FADT has no (supported) reset register.
+    NoResetRegister,
+
+    /// System still runs after the operation.
+    Ignored,
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// `SCI_EN` bit of PM1 control, set when in ACPI mode.
+const SCI_ENABLE: u16 = 1 << 0;
+
+/// `SLP_TYPx` bits of PM1 control.
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_TYPE_MASK: u16 = 0b111 << SLEEP_TYPE_SHIFT;
+
+/// `SLP_EN` bit of PM1 control, entering the sleep state.
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// `RESET_REG_SUP` flag of FADT.
+const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+/// AML opcodes found in the `\_S5` package.
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+
+/// 8042 keyboard controller's status and command port.
+const KBC_COMMAND_PORT: u16 = 0x64;
+
+/// 8042 command pulsing the CPU reset line.
+const KBC_RESET: u8 = 0xfe;
+
+/// Iterations waited for hardware before giving up on it.
+const TIMEOUT: u32 = 1_000_000;
+
+/// Virtual address of a memory-mapped reset register, once mapped
+/// (zero until then).
+static RESET_REGISTER: AtomicU64 = AtomicU64::new(0);
+
+/**
+ *  Enters sleep state S5 (soft off).
+ */
+fn acpi_shutdown() -> Result<(), PowerError> {
+    let fadt = acpi::fadt().ok_or(PowerError::NoFadt)?;
+    let (sleep_type_a, sleep_type_b) = acpi::dsdt()
+        .and_then(s5_sleep_types)
+        .ok_or(PowerError::NoSleepType)?;
+    enable_acpi_mode(&fadt)?;
+    unsafe {
+        enter_sleep_state(fadt.pm1a_control as u16, sleep_type_a);
+        if fadt.pm1b_control != 0 {
+            enter_sleep_state(fadt.pm1b_control as u16, sleep_type_b);
+        }
+    }
+    wait();
+    Err(PowerError::Ignored)
+}
+
+/**
+ *  Switches into ACPI mode, unless already in it (or hardware
+ *  has no legacy mode, as told by a zero SMI command port).
+ */
+fn enable_acpi_mode(fadt: &acpi::Fadt) -> Result<(), PowerError> {
+    let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control as u16);
+    if fadt.smi_command == 0
+        || unsafe { pm1a_control.read() } & SCI_ENABLE != 0
+    {
+        return Ok(());
+    }
+    unsafe { Port::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    for _ in 0..TIMEOUT {
+        if unsafe { pm1a_control.read() } & SCI_ENABLE != 0 {
+            return Ok(());
+        }
+        spin_loop();
+    }
+    Err(PowerError::AcpiModeTimeout)
+}
+
+/**
+ *  Writes sleep type along with `SLP_EN` to a PM1 control block.
+ */
+unsafe fn enter_sleep_state(port: u16, sleep_type: u8) {
+    let mut port = Port::<u16>::new(port);
+    let control = port.read() & !SLEEP_TYPE_MASK;
+    let sleep_type = (sleep_type as u16) << SLEEP_TYPE_SHIFT;
+    port.write(control | (sleep_type & SLEEP_TYPE_MASK) | SLEEP_ENABLE);
+}
+
+/**
+ *  Finds the `SLP_TYPa` and `SLP_TYPb` values of S5 in DSDT's AML.
+ *
+ *  Rather than interpreting AML, looks for its encoding of
+ *  `Name (_S5, Package () { a, b, ... })`, as most firmware has.
+ */
+fn s5_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| name == b"_S5_")
+        .find_map(|(pos, _)| {
+            // Must be named by `Name`, possibly with a root prefix
+            let named = aml[..pos].ends_with(&[NAME_OP])
+                || aml[..pos].ends_with(&[NAME_OP, b'\\']);
+            if !named {
+                return None;
+            }
+            let mut bytes = aml[pos + 4..].iter().copied();
+            if bytes.next()? != PACKAGE_OP {
+                return None;
+            }
+            // Package length's first byte tells how many bytes follow it
+            let length = bytes.next()?;
+            for _ in 0..length >> 6 {
+                bytes.next()?;
+            }
+            let _element_count = bytes.next()?;
+            Some((aml_byte(&mut bytes)?, aml_byte(&mut bytes)?))
+        })
+}
+
+/**
+ *  Decodes an AML integer fitting in a byte.
+ */
+fn aml_byte<I: Iterator<Item = u8>>(bytes: &mut I) -> Option<u8> {
+    match bytes.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => bytes.next(),
+        _ => None,
+    }
+}
+
+/**
+ *  Writes the reset value to FADT's reset register.
+ */
+fn acpi_reset() -> Result<(), PowerError> {
+    let fadt = acpi::fadt().ok_or(PowerError::NoFadt)?;
+    let register = fadt.reset_register;
+    if fadt.flags & RESET_REGISTER_SUPPORTED == 0 || register.address == 0 {
+        return Err(PowerError::NoResetRegister);
+    }
+    match register.address_space {
+        GenericAddress::SYSTEM_IO => unsafe {
+            Port::new(register.address as u16).write(fadt.reset_value);
+        }
+        GenericAddress::SYSTEM_MEMORY => unsafe {
+            let addr = map_reset_register(register.address)
+                .ok_or(PowerError::NoResetRegister)?;
+            addr.as_mut_ptr::<u8>().write_volatile(fadt.reset_value);
+        }
+        _ => return Err(PowerError::NoResetRegister),
+    }
+    wait();
+    Err(PowerError::Ignored)
+}
+
+/**
+ *  Maps the memory-mapped reset register at physical `addr`,
+ *  only on first call, returning its virtual address.
+ */
+fn map_reset_register(addr: u64) -> Option<VirtAddr> {
+    let mapped = RESET_REGISTER.load(Ordering::Acquire);
+    if mapped != 0 {
+        return Some(VirtAddr::new(mapped));
+    }
+    let addr = PhysAddr::try_new(addr).ok()?;
+    let addr = unsafe { memory::map_mmio(addr, 1) }.ok()?;
+    RESET_REGISTER.store(addr.as_u64(), Ordering::Release);
+    Some(addr)
+}
+
+/**
+ *  Pulses CPU reset line through the 8042 keyboard controller,
+ *  once its input buffer is empty.
+ */
+fn keyboard_controller_reset() {
+    let mut port = Port::<u8>::new(KBC_COMMAND_PORT);
+    for _ in 0..TIMEOUT {
+        if unsafe { port.read() } & 0b10 == 0 {
+            break;
+        }
+        spin_loop();
+    }
+    unsafe { port.write(KBC_RESET) };
+    wait();
+}
+
+/**
+ *  Resets CPU by raising an exception without any IDT, which
+ *  ends up in a triple fault.
+ */
+fn triple_fault() -> ! {
+    let empty_idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty_idt);
+        asm!("int3", options(noreturn));
+    }
+}
+
+/**
+ *  Gives hardware some time to carry out a power operation.
+ */
+fn wait() {
+    for _ in 0..TIMEOUT {
+        spin_loop();
+    }
+}
+
+/**
+ *  Halts forever, with interrupts disabled.
+ */
+fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        instructions::hlt();
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Finds S5 sleep types in AML encoded with different integer forms.
+ */
+#[test_case]
+fn test_s5_sleep_types() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+    let aml = [0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0a,
+        0x04, 0x0a, 0x05, 0x00, 0x00, 0x00];
+    assert_eq!(s5_sleep_types(&aml), Some((5, 0)));
+
+    // Name (_S5, Package (0x02) { One, 0x07 }), with 2-byte length
+    let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x00, 0x02,
+        0x01, 0x0a, 0x07];
+    assert_eq!(s5_sleep_types(&aml), Some((1, 7)));
+
+    // Not a named object
+    let aml = [0x14, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x00, 0x00];
+    assert_eq!(s5_sleep_types(&aml), None);
+}
+
+/**
+ *  Checks that QEMU's DSDT has the S5 package shutdown relies on.
+ */
+#[test_case]
+fn test_dsdt_has_s5() {
+    let dsdt = acpi::dsdt().expect("DSDT not found");
+    assert!(s5_sleep_types(dsdt).is_some());
+}
@@ -0,0 +1,462 @@
+/*!
+ *  PS/2 (8042) controller.
+ *
+ *  Controller has up to two ports, the first one usually taking a
+ *  keyboard (on IRQ 1) and the second one a mouse (on IRQ 12). Both
+ *  controller and devices are driven through the data port, 0x60,
+ *  and the status (read) and command (write) register, 0x64.
+ *
+ *  On `init`, controller is brought to a known state instead of
+ *  relying on firmware's: ports are disabled, controller and ports
+ *  are tested, and each attached device is reset and identified.
+ *  Keyboard scancodes are translated to Set 1, as `keyboard` expects.
+ */
+
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+use crate::time;
+
+/**
+ *  Initializes controller and resets attached devices, enabling
+ *  interrupts of working ones.
+ *
+ *  Must be run with interrupts disabled, so that handlers don't take
+ *  controller's responses. Failures of a single port are only
+ *  reported by `device`.
+ */
+pub fn init() -> Result<(), Ps2Error> {
+    let mut controller = CONTROLLER.lock();
+    let result = controller.init();
+    *DEVICES.lock() = match result {
+        Ok(devices) => devices,
+        Err(err) => [Err(err), Err(err)],
+    };
+    result.map(|_| ())
+}
+
+/**
+ *  Gets the device attached to `channel`, as found on `init`.
+ */
+pub fn device(channel: Channel) -> Result<Device, Ps2Error> {
+    DEVICES.lock()[channel.index()]
+}
+
+/**
+ *  If a keyboard is attached to the first port.
+ */
+pub fn has_keyboard() -> bool {
+    device(Channel::First) == Ok(Device::Keyboard)
+}
+
+/**
+ *  Sends `bytes` to device on `channel`, one by one, waiting for
+ *  each acknowledgement.
+ *
+ *  Device's IRQ must be masked (or interrupts disabled), so that
+ *  its handler doesn't take acknowledgements.
+ */
+pub(crate) fn send(channel: Channel, bytes: &[u8]) -> Result<(), Ps2Error> {
+    let mut controller = CONTROLLER.lock();
+    for &byte in bytes {
+        controller.send(channel, byte)?;
+    }
+    Ok(())
+}
+
+/**
+ *  Reads a byte sent by a device in response to a command,
+ *  under the same conditions as `send`.
+ */
+pub(crate) fn read_response() -> Result<u8, Ps2Error> {
+    CONTROLLER.lock().read_data(COMMAND_TIMEOUT)
+}
+
+/**
+ *  Reads a byte sent by the keyboard (on first port), if there's one,
+ *  without locking the controller. Called by interrupt handlers only.
+ */
+pub(crate) fn read_keyboard_output() -> Option<u8> {
+    read_output(false)
+}
+
+/**
+ *  Reads a byte sent by the mouse (on second port), if there's one,
+ *  without locking the controller. Called by interrupt handlers only.
+ */
+pub(crate) fn read_mouse_output() -> Option<u8> {
+    read_output(true)
+}
+
+/**
+ *  Reads the waiting output byte, if it came from the second port
+ *  (`from_second`) or else the first one, leaving it there otherwise.
+ */
+fn read_output(from_second: bool) -> Option<u8> {
+    let mut command = Port::<u8>::new(COMMAND_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    unsafe {
+        let status = command.read();
+        let full = status & OUTPUT_FULL != 0;
+        if full && (status & SECOND_OUTPUT != 0) == from_second {
+            Some(data.read())
+        } else {
+            None
+        }
+    }
+}
+
+/**
+ *  Controller ports.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// First port, on IRQ 1.
+    First,
+
+    /// Second port, on IRQ 12 (if controller has it).
+    Second,
+}
+
+impl Channel {
+    /// Index into per-port arrays.
+    fn index(self) -> usize {
+        match self {
+            Channel::First => 0,
+            Channel::Second => 1,
+        }
+    }
+}
+
+/**
+ *  Devices attached to a port, as identified by their ID bytes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    /// AT or MF2 keyboard.
+    Keyboard,
+
+    /// Mouse, with its ID (0 if standard, 3 or 4 if IntelliMouse).
+    Mouse(u8),
+
+    /// Device with unknown ID bytes.
+    Unknown(u8, Option<u8>),
+}
+
+/**
+ *  Errors returned on PS/2 operations.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// `init` hasn't run yet.
+    NotInitialized,
+
+    /// Controller didn't respond in time (or isn't there).
+    Timeout,
+
+    /// Controller self-test failed, with its response.
+    SelfTestFailed(u8),
+
+    /// Controller has a single port.
+    NoPort,
+
+    /// Port test failed, with its response.
+    PortTestFailed(u8),
+
+    /// No device responded on port.
+    NoDevice,
+
+    /// Device responded with something else than an acknowledgement.
+    NoAck(u8),
+
+    /// Device self-test failed, with its response.
+    DeviceTestFailed(u8),
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// I/O ports.
+const DATA_PORT: u16 = 0x60;
+const COMMAND_PORT: u16 = 0x64;
+
+/// Controller commands.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xa7;
+const ENABLE_SECOND: u8 = 0xa8;
+const TEST_SECOND: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST: u8 = 0xab;
+const DISABLE_FIRST: u8 = 0xad;
+const ENABLE_FIRST: u8 = 0xae;
+const WRITE_SECOND: u8 = 0xd4;
+
+/// Controller responses.
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// Device commands.
+const IDENTIFY: u8 = 0xf2;
+const ENABLE_SCANNING: u8 = 0xf4;
+const DISABLE_SCANNING: u8 = 0xf5;
+const RESET: u8 = 0xff;
+
+/// Device responses.
+const DEVICE_TEST_PASSED: u8 = 0xaa;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+/// Status register bits.
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+const SECOND_OUTPUT: u8 = 1 << 5;
+
+/// Configuration byte bits.
+const FIRST_IRQ: u8 = 1 << 0;
+const SECOND_IRQ: u8 = 1 << 1;
+const SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const TRANSLATION: u8 = 1 << 6;
+
+/// Interval between status polls.
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// Time allowed for controller and command responses.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Time allowed for device self-test, after a reset.
+const RESET_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Times a device command is resent, when asked to.
+const MAX_RETRIES: usize = 3;
+
+/// Maximum bytes discarded on flush (the buffer only takes one).
+const MAX_FLUSH: usize = 16;
+
+/**
+ *  Controller registers.
+ */
+struct Controller {
+    /// Data port.
+    data: Port<u8>,
+
+    /// Status (read) and command (write) register.
+    command: Port<u8>,
+}
+
+/// Controller, locked so that commands and responses aren't interleaved.
+static CONTROLLER: spin::Mutex<Controller> = spin::Mutex::new(Controller {
+    data: Port::new(DATA_PORT),
+    command: Port::new(COMMAND_PORT),
+});
+
+/// Device found on each port by `init`.
+static DEVICES: spin::Mutex<[Result<Device, Ps2Error>; 2]> =
+    spin::Mutex::new([Err(Ps2Error::NotInitialized); 2]);
+
+impl Controller {
+    /**
+     *  Runs the whole initialization sequence, returning what's
+     *  attached to each port.
+     */
+    fn init(&mut self) -> Result<[Result<Device, Ps2Error>; 2], Ps2Error> {
+        // Devices must not send anything meanwhile
+        self.command(DISABLE_FIRST)?;
+        self.command(DISABLE_SECOND)?;
+        self.flush();
+
+        let config = self.read_config()?
+            & !(FIRST_IRQ | SECOND_IRQ | TRANSLATION);
+        self.write_config(config)?;
+        self.command(SELF_TEST)?;
+        match self.read_data(COMMAND_TIMEOUT)? {
+            SELF_TEST_PASSED => {}
+            response => return Err(Ps2Error::SelfTestFailed(response)),
+        }
+        // Self-test may reset the controller
+        self.write_config(config)?;
+
+        // Second port exists if enabling it starts its clock
+        let mut has_second = false;
+        if config & SECOND_CLOCK_DISABLED != 0 {
+            self.command(ENABLE_SECOND)?;
+            has_second = self.read_config()? & SECOND_CLOCK_DISABLED == 0;
+            self.command(DISABLE_SECOND)?;
+        }
+
+        let mut devices = [Err(Ps2Error::NoDevice); 2];
+        let channels = [
+            (Channel::First, TEST_FIRST, ENABLE_FIRST),
+            (Channel::Second, TEST_SECOND, ENABLE_SECOND),
+        ];
+        for (channel, test, enable) in channels {
+            if channel == Channel::Second && !has_second {
+                devices[channel.index()] = Err(Ps2Error::NoPort);
+                continue;
+            }
+            self.command(test)?;
+            match self.read_data(COMMAND_TIMEOUT)? {
+                PORT_TEST_PASSED => {}
+                response => {
+                    devices[channel.index()] =
+                        Err(Ps2Error::PortTestFailed(response));
+                    continue;
+                }
+            }
+            self.command(enable)?;
+            devices[channel.index()] = self.reset_device(channel);
+        }
+
+        // Only working devices raise interrupts
+        let mut config = self.read_config()?;
+        if devices[0].is_ok() {
+            config |= FIRST_IRQ | TRANSLATION;
+        }
+        if devices[1].is_ok() {
+            config |= SECOND_IRQ;
+        }
+        self.write_config(config)?;
+        Ok(devices)
+    }
+
+    /**
+     *  Resets and identifies device on `channel`, enabling scanning
+     *  if it's a keyboard.
+     */
+    fn reset_device(&mut self, channel: Channel) -> Result<Device, Ps2Error> {
+        self.send(channel, RESET).map_err(|err| match err {
+            Ps2Error::Timeout => Ps2Error::NoDevice,
+            err => err,
+        })?;
+        match self.read_data(RESET_TIMEOUT)? {
+            DEVICE_TEST_PASSED => {}
+            response => return Err(Ps2Error::DeviceTestFailed(response)),
+        }
+        // Mice send their ID right after passing
+        self.read_data(COMMAND_TIMEOUT).ok();
+
+        self.send(channel, DISABLE_SCANNING)?;
+        self.flush();
+        self.send(channel, IDENTIFY)?;
+        let first = self.read_data(COMMAND_TIMEOUT).ok();
+        let second = match first {
+            Some(_) => self.read_data(COMMAND_TIMEOUT).ok(),
+            None => None,
+        };
+        let device = identify(first, second);
+        if device == Device::Keyboard {
+            self.send(channel, ENABLE_SCANNING)?;
+        }
+        Ok(device)
+    }
+
+    /**
+     *  Sends a byte to device on `channel`, waiting for its
+     *  acknowledgement (resending if asked to).
+     */
+    fn send(&mut self, channel: Channel, byte: u8) -> Result<(), Ps2Error> {
+        for _ in 0..MAX_RETRIES {
+            if channel == Channel::Second {
+                self.command(WRITE_SECOND)?;
+            }
+            self.write_data(byte)?;
+            match self.read_data(COMMAND_TIMEOUT)? {
+                ACK => return Ok(()),
+                RESEND => continue,
+                response => return Err(Ps2Error::NoAck(response)),
+            }
+        }
+        Err(Ps2Error::NoAck(RESEND))
+    }
+
+    /// Reads configuration byte.
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.command(READ_CONFIG)?;
+        self.read_data(COMMAND_TIMEOUT)
+    }
+
+    /// Writes configuration byte.
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    /// Sends a command to the controller itself.
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait(INPUT_FULL, false, COMMAND_TIMEOUT)?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    /// Writes a byte to data port, once controller can take it.
+    fn write_data(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait(INPUT_FULL, false, COMMAND_TIMEOUT)?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    /// Reads a byte from data port, waiting up to `timeout` for it.
+    fn read_data(&mut self, timeout: Duration) -> Result<u8, Ps2Error> {
+        self.wait(OUTPUT_FULL, true, timeout)?;
+        Ok(unsafe { self.data.read() })
+    }
+
+    /// Discards whatever is left in the output buffer.
+    fn flush(&mut self) {
+        for _ in 0..MAX_FLUSH {
+            if unsafe { self.command.read() } & OUTPUT_FULL == 0 {
+                break;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    /// Polls status register until `flag` is `set` (or clear).
+    fn wait(&mut self, flag: u8, set: bool, timeout: Duration)
+        -> Result<(), Ps2Error>
+    {
+        let polls = timeout.as_micros() / POLL_INTERVAL.as_micros();
+        for _ in 0..=polls {
+            if (unsafe { self.command.read() } & flag != 0) == set {
+                return Ok(());
+            }
+            time::reference_wait(POLL_INTERVAL);
+        }
+        Err(Ps2Error::Timeout)
+    }
+}
+
+/**
+ *  Identifies a device by the bytes it sends on `IDENTIFY`.
+ */
+fn identify(first: Option<u8>, second: Option<u8>) -> Device {
+    match first {
+        // AT keyboards send no ID, MF2 ones two bytes starting with 0xab
+        None | Some(0xab) | Some(0xac) => Device::Keyboard,
+        Some(id @ (0x00 | 0x03 | 0x04)) => Device::Mouse(id),
+        Some(id) => Device::Unknown(id, second),
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Identifies devices by their ID bytes.
+ */
+#[test_case]
+fn test_identify() {
+    assert_eq!(identify(None, None), Device::Keyboard);
+    assert_eq!(identify(Some(0xab), Some(0x41)), Device::Keyboard);
+    assert_eq!(identify(Some(0x03), None), Device::Mouse(3));
+    assert_eq!(identify(Some(0x10), Some(0x20)),
+        Device::Unknown(0x10, Some(0x20)));
+}
+
+/**
+ *  Checks devices found by QEMU's default keyboard and mouse.
+ */
+#[test_case]
+fn test_devices() {
+    assert!(has_keyboard(), "no keyboard: {:?}", device(Channel::First));
+    assert_eq!(device(Channel::Second), Ok(Device::Mouse(0)));
+}
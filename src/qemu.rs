@@ -1,7 +1,9 @@
 /*!
  *  QEMU's shutdown procedures.
  * 
- *  Uses port-mapping I/O to `isa-debug-exit`.
+ *  Uses port-mapping I/O to `isa-debug-exit`, which is only attached
+ *  on tests (for reporting their outcome through the exit code).
+ *  Normal runs power off and reboot through `power` instead.
  */
 
 use x86_64::instructions::port::Port;
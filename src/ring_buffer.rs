@@ -0,0 +1,146 @@
+/*!
+ *  Lock-free fixed-capacity queue, for passing data out of interrupt
+ *  handlers.
+ *
+ *  Queue has a single producer (usually an interrupt handler) and a
+ *  single consumer, so neither ever waits for the other, nor has to
+ *  disable interrupts. When full, pushed items are dropped and counted.
+ */
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/**
+ *  Single-producer single-consumer ring buffer of `N` items.
+ *
+ *  Head and tail only ever increase (wrapping around), slots being
+ *  taken modulo capacity, so full and empty states are told apart.
+ */
+pub struct RingBuffer<T, const N: usize> {
+    /// Items, initialized from head up to tail.
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+
+    /// Position of next item to be read (written by consumer).
+    head: AtomicUsize,
+
+    /// Position of next item to be written (written by producer).
+    tail: AtomicUsize,
+
+    /// Items dropped on overflow.
+    dropped: AtomicU64,
+}
+
+// Slots are only shared through head and tail handoffs
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    /**
+     *  Creates an empty queue.
+     */
+    pub const fn new() -> Self {
+        RingBuffer {
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /**
+     *  If no item is waiting.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Acquire)
+    }
+
+    /**
+     *  Gets the number of items dropped due to a full queue.
+     */
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Pointer to slot of position `index`.
+    fn slot(&self, index: usize) -> *mut T {
+        let items = self.buffer.get() as *mut T;
+        unsafe { items.add(index % N) }
+    }
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /**
+     *  Appends an item, dropping it if queue is full.
+     *
+     *  Unsafe since there must be a single producer, that is, `push`
+     *  must not be called again until it returns.
+     */
+    pub unsafe fn push(&self, item: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.slot(tail).write(item);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /**
+     *  Takes the oldest item.
+     *
+     *  Unsafe since there must be a single consumer, that is, `pop`
+     *  must not be called again until it returns.
+     */
+    pub unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let item = self.slot(head).read();
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Fills a queue, checking that overflowing items are dropped
+ *  and the rest are read in order.
+ */
+#[test_case]
+fn test_overflow() {
+    const CAPACITY: usize = 8;
+    let queue = RingBuffer::<u16, CAPACITY>::new();
+    unsafe {
+        for i in 0..CAPACITY + 3 {
+            assert_eq!(queue.push(i as u16), i < CAPACITY);
+        }
+        assert_eq!(queue.dropped(), 3);
+        for i in 0..CAPACITY {
+            assert_eq!(queue.pop(), Some(i as u16));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+    assert!(queue.is_empty());
+}
+
+/**
+ *  Pushes and pops past capacity, wrapping around the buffer.
+ */
+#[test_case]
+fn test_wrap_around() {
+    let queue = RingBuffer::<u8, 4>::new();
+    unsafe {
+        for i in 0..10 {
+            assert!(queue.push(i));
+            assert!(queue.push(i + 100));
+            assert_eq!(queue.pop(), Some(i));
+            assert_eq!(queue.pop(), Some(i + 100));
+        }
+    }
+    assert_eq!(queue.dropped(), 0);
+}
@@ -0,0 +1,389 @@
+/*!
+ *  CMOS Real-Time Clock (RTC) and wall-clock time.
+ *
+ *  RTC keeps calendar date and time in battery backed CMOS registers,
+ *  accessed through an index port and a data port. Values are either
+ *  BCD or binary and hours either 12 or 24-hour based, as told by
+ *  status register B. Whilst RTC updates them (once a second), reads
+ *  may be inconsistent, so they're only trusted when twice the same.
+ *
+ *  RTC can also raise IRQ 8 periodically and/or after each update.
+ *  That is unavailable when HPET is the tick source, as its legacy
+ *  replacement mode takes IRQ 8 over.
+ */
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::acpi;
+use crate::interrupts;
+use crate::time::{self, TickSource};
+
+/// ISA IRQ raised by RTC.
+pub const IRQ: u8 = 8;
+
+/**
+ *  Reads current date and time.
+ */
+pub fn now() -> DateTime {
+    let century_register = acpi::fadt()
+        .map(|fadt| fadt.century)
+        .filter(|&register| register != 0);
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let mut last = cmos.read_raw(century_register);
+        loop {
+            let raw = cmos.read_raw(century_register);
+            if raw == last {
+                return raw.decode(cmos.read(STATUS_B));
+            }
+            last = raw;
+        }
+    })
+}
+
+/**
+ *  Enables RTC interrupt `kind` on IRQ 8 (unmasking it).
+ */
+pub fn enable_interrupt(kind: RtcInterrupt) -> Result<(), RtcError> {
+    if time::tick_source() == TickSource::Hpet {
+        return Err(RtcError::IrqUnavailable);
+    }
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        if let RtcInterrupt::Periodic(rate) = kind {
+            if !(3..=15).contains(&rate) {
+                return Err(RtcError::InvalidRate(rate));
+            }
+            let status_a = cmos.read(STATUS_A);
+            cmos.write(STATUS_A, (status_a & 0xf0) | rate);
+        }
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | kind.enable_bit());
+
+        // Clears any pending interrupt, which would otherwise block IRQ 8
+        cmos.read(STATUS_C);
+        Ok(())
+    })?;
+    interrupts::enable_irq(IRQ).map_err(|_| RtcError::IrqUnavailable)
+}
+
+/**
+ *  Disables RTC interrupt `kind` (IRQ 8 is left as it is).
+ */
+pub fn disable_interrupt(kind: RtcInterrupt) {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !kind.enable_bit());
+    });
+}
+
+/**
+ *  Gets the number of periodic and update interrupts taken so far.
+ */
+pub fn interrupt_counts() -> (u64, u64) {
+    (PERIODIC_COUNT.load(Ordering::Relaxed),
+        UPDATE_COUNT.load(Ordering::Relaxed))
+}
+
+/**
+ *  Acknowledges an RTC interrupt, counting it by its kind.
+ *  Called by the IRQ 8 handler only.
+ */
+pub(crate) fn handle_interrupt() {
+    // Reading status C is required for further interrupts to happen
+    let status_c = CMOS.lock().read(STATUS_C);
+    if status_c & PERIODIC_FLAG != 0 {
+        PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if status_c & UPDATE_FLAG != 0 {
+        UPDATE_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ *  RTC interrupt kinds.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcInterrupt {
+    /// At `32768 >> (rate - 1)` Hz, for `rate` from 3 (8192 Hz)
+    /// to 15 (2 Hz).
+    Periodic(u8),
+
+    /// After each update, once a second.
+    Update,
+}
+
+impl RtcInterrupt {
+    /// Enable bit of status register B.
+    fn enable_bit(self) -> u8 {
+        match self {
+            RtcInterrupt::Periodic(_) => 1 << 6,
+            RtcInterrupt::Update => 1 << 4,
+        }
+    }
+}
+
+/**
+ *  Errors returned on RTC interrupt enabling.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// Periodic rate isn't between 3 and 15.
+    InvalidRate(u8),
+
+    /// IRQ 8 is taken over by HPET, or couldn't be unmasked.
+    IrqUnavailable,
+}
+
+/**
+ *  Calendar date and time (UTC, as RTC is usually kept).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    /// Full year (e.g. 2021).
+    pub year: u16,
+
+    /// Month, from 1 to 12.
+    pub month: u8,
+
+    /// Day of month, from 1 to 31.
+    pub day: u8,
+
+    /// Hour, from 0 to 23.
+    pub hour: u8,
+
+    /// Minute, from 0 to 59.
+    pub minute: u8,
+
+    /// Second, from 0 to 59.
+    pub second: u8,
+}
+
+impl DateTime {
+    /**
+     *  Gets the number of seconds since Unix epoch
+     *  (1970-01-01 00:00:00 UTC).
+     */
+    pub fn unix_timestamp(&self) -> u64 {
+        // Counts days from March, so that leap days end each year
+        let (year, month) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let days = 365 * year + year / 4 - year / 100 + year / 400
+            + (153 * month + 2) / 5 + self.day as u64 - 1
+            - DAYS_TO_EPOCH;
+        days * 86400 + self.hour as u64 * 3600
+            + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    /**
+     *  Formats as ISO 8601 (e.g. `2021-09-26 13:07:42`).
+     */
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day,
+            self.hour, self.minute, self.second)
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// CMOS register indices.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+/// Update-in-progress flag of status register A.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+
+/// 24-hour format and binary mode flags of status register B.
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+
+/// PM flag of hours register, in 12-hour format.
+const HOUR_PM: u8 = 1 << 7;
+
+/// Interrupt kind flags of status register C.
+const PERIODIC_FLAG: u8 = 1 << 6;
+const UPDATE_FLAG: u8 = 1 << 4;
+
+/// Days from March 1st of year 0 to Unix epoch, as counted above.
+const DAYS_TO_EPOCH: u64 = 719_468;
+
+/// Periodic interrupts taken.
+static PERIODIC_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Update interrupts taken.
+static UPDATE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/**
+ *  CMOS index and data ports.
+ */
+struct Cmos {
+    /// Index port (bit 7 disables NMIs, so it's kept clear).
+    index: Port<u8>,
+
+    /// Data port.
+    data: Port<u8>,
+}
+
+impl Cmos {
+    /// Reads a register.
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    /// Writes a register.
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    /// Reads date and time registers as they are, once no update
+    /// is in progress.
+    fn read_raw(&mut self, century_register: Option<u8>) -> RawDateTime {
+        while self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        RawDateTime {
+            century: century_register.map(|register| self.read(register)),
+            year: self.read(YEAR),
+            month: self.read(MONTH),
+            day: self.read(DAY),
+            hour: self.read(HOURS),
+            minute: self.read(MINUTES),
+            second: self.read(SECONDS),
+        }
+    }
+}
+
+/// CMOS, locked so that index and data accesses aren't interleaved.
+static CMOS: spin::Mutex<Cmos> = spin::Mutex::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+/**
+ *  Date and time registers, still in RTC's format.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawDateTime {
+    century: Option<u8>,
+    year: u8,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+impl RawDateTime {
+    /**
+     *  Decodes registers, given status register B's format flags.
+     *
+     *  Without a century register, years are taken as 20xx.
+     */
+    fn decode(&self, status_b: u8) -> DateTime {
+        let value = |raw: u8| {
+            if status_b & BINARY != 0 { raw } else { from_bcd(raw) }
+        };
+        let mut hour = value(self.hour & !HOUR_PM);
+        if status_b & HOUR_24 == 0 {
+            // 12 AM is midnight and 12 PM is noon
+            hour %= 12;
+            if self.hour & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+        let century = self.century.map_or(20, value) as u16;
+        DateTime {
+            year: century * 100 + value(self.year) as u16,
+            month: value(self.month),
+            day: value(self.day),
+            hour,
+            minute: value(self.minute),
+            second: value(self.second),
+        }
+    }
+}
+
+/**
+ *  Converts a BCD byte to binary.
+ */
+fn from_bcd(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0x0f)
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Decodes BCD and binary registers, in 12 and 24-hour formats.
+ */
+#[test_case]
+fn test_decode() {
+    let raw = RawDateTime {
+        century: Some(0x20),
+        year: 0x21,
+        month: 0x09,
+        day: 0x26,
+        hour: 0x12 | HOUR_PM,
+        minute: 0x07,
+        second: 0x42,
+    };
+    let expected = DateTime {
+        year: 2021, month: 9, day: 26, hour: 12, minute: 7, second: 42
+    };
+    assert_eq!(raw.decode(0), expected);
+    let raw = RawDateTime { hour: 0x12, ..raw };
+    assert_eq!(raw.decode(0).hour, 0);
+    let raw = RawDateTime { hour: 13, minute: 7, second: 42, century: None,
+        year: 21, month: 9, day: 26 };
+    let expected = DateTime { hour: 13, ..expected };
+    assert_eq!(raw.decode(BINARY | HOUR_24), expected);
+    assert_eq!(expected.unix_timestamp(), 1_632_661_662);
+}
+
+/**
+ *  Reads RTC, which must hold a plausible date.
+ */
+#[test_case]
+fn test_now() {
+    let now = now();
+    assert!(now.year >= 2021);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+/**
+ *  Enables periodic interrupts at 1024 Hz, which must be taken.
+ */
+#[test_case]
+fn test_periodic_interrupt() {
+    if time::tick_source() == TickSource::Hpet {
+        return;
+    }
+    let (start, _) = interrupt_counts();
+    enable_interrupt(RtcInterrupt::Periodic(6)).unwrap();
+    time::reference_wait(core::time::Duration::from_millis(20));
+    disable_interrupt(RtcInterrupt::Periodic(6));
+    assert!(interrupt_counts().0 > start);
+}
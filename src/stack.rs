@@ -0,0 +1,310 @@
+/*!
+ *  Kernel stacks, each with an unmapped guard page beneath it.
+ *
+ *  Stacks are allocated from a dedicated virtual memory area, one
+ *  right after the other's guard page, so that overflowing a stack
+ *  faults on its guard page instead of silently corrupting memory.
+ *  Every stack is kept in a registry, used for recognizing guard page
+ *  hits and for validating frames on backtraces.
+ *
+ *  The kernel stack itself is mapped by the bootloader, which
+ *  also leaves its first page unmapped as a guard page. Its bounds
+ *  are found on `init`, by walking its pages, so that they follow
+ *  bootloader's configuration.
+ */
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{page::PageRange, Page, PageTableFlags},
+    VirtAddr
+};
+
+use crate::memory::{self, MapError};
+use crate::page_fault;
+
+/// Starting address of the area stacks are allocated from.
+pub const STACKS_START: u64 = 0x_6666_0000_0000;
+
+/// Size of the area stacks are allocated from (1 GiB).
+pub const STACKS_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Maximum number of allocated stacks.
+pub const MAX_STACKS: usize = 16;
+
+/**
+ *  Finds the kernel stack and registers its guard page for page
+ *  fault dispatching.
+ *
+ *  Requires `memory::init` to have been called beforehand.
+ */
+pub fn init() {
+    let stack = kernel_stack().expect("kernel stack not mapped");
+    register_guard(&stack).expect("kernel stack guard failed");
+    without_interrupts(|| REGISTRY.lock().kernel = Some(stack));
+}
+
+/**
+ *  Allocates a stack with `pages` mapped pages above a guard page.
+ */
+pub fn allocate(name: &'static str, pages: u64)
+    -> Result<Stack, StackError>
+{
+    let size = (pages + 1) * PAGE_SIZE;
+    without_interrupts(|| {
+        // Registry stays locked, so that range is taken only once mapped
+        let mut registry = REGISTRY.lock();
+        if registry.len == MAX_STACKS {
+            return Err(StackError::TooManyStacks);
+        }
+        if pages == 0 || registry.next + size > STACKS_START + STACKS_SIZE {
+            return Err(StackError::OutOfAddressSpace);
+        }
+        let guard = registry.next;
+        let stack = Stack {
+            name,
+            bottom: VirtAddr::new(guard + PAGE_SIZE),
+            top: VirtAddr::new(guard + size),
+        };
+        map_stack(&stack)?;
+        if let Err(err) = register_guard(&stack) {
+            unmap_pages(stack_pages(&stack))?;
+            return Err(err.into());
+        }
+        registry.next += size;
+        let len = registry.len;
+        registry.stacks[len] = Some(stack);
+        registry.len += 1;
+        Ok(stack)
+    })
+}
+
+/**
+ *  Finds the stack containing given address.
+ */
+pub fn containing(addr: VirtAddr) -> Option<Stack> {
+    find(|stack| stack.contains(addr))
+}
+
+/**
+ *  Finds the stack whose guard page contains given address,
+ *  which has thus been overflowed.
+ */
+pub fn overflowed(addr: VirtAddr) -> Option<Stack> {
+    find(|stack| stack.guard_page() == Page::containing_address(addr))
+}
+
+/**
+ *  Stack's mapped address range.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    /// Name identifying stack in messages.
+    pub name: &'static str,
+
+    /// Lowest mapped address (right above guard page).
+    pub bottom: VirtAddr,
+
+    /// Highest (exclusive) address, where stack starts growing down.
+    pub top: VirtAddr,
+}
+
+impl Stack {
+    /// If stack contains given address.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.bottom <= addr && addr < self.top
+    }
+
+    /// Unmapped page beneath stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - 1u64)
+    }
+}
+
+/**
+ *  Errors returned on stack allocation.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    /// All `MAX_STACKS` slots are taken.
+    TooManyStacks,
+
+    /// Stacks area has no room left (or zero pages were requested).
+    OutOfAddressSpace,
+
+    /// Mapping stack pages failed.
+    MapFailed(MapError),
+
+    /// Registering guard page failed.
+    GuardFailed(page_fault::RegisterError),
+}
+
+impl From<MapError> for StackError {
+    fn from(err: MapError) -> Self {
+        StackError::MapFailed(err)
+    }
+}
+
+impl From<page_fault::RegisterError> for StackError {
+    fn from(err: page_fault::RegisterError) -> Self {
+        StackError::GuardFailed(err)
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Size of a page in bytes.
+const PAGE_SIZE: u64 = 4096;
+
+/**
+ *  Known stacks and next free address of stacks area.
+ */
+struct Registry {
+    /// Kernel stack, found on `init`.
+    kernel: Option<Stack>,
+
+    /// Stacks in allocation order (first `len` ones are `Some`).
+    stacks: [Option<Stack>; MAX_STACKS],
+
+    /// Number of allocated stacks.
+    len: usize,
+
+    /// Where the next stack's guard page goes.
+    next: u64,
+}
+
+/// Stacks registry.
+static REGISTRY: spin::Mutex<Registry> = spin::Mutex::new(Registry {
+    kernel: None,
+    stacks: [None; MAX_STACKS],
+    len: 0,
+    next: STACKS_START,
+});
+
+/**
+ *  Finds the first stack (kernel one included) satisfying `predicate`.
+ */
+fn find<P: Fn(&Stack) -> bool>(predicate: P) -> Option<Stack> {
+    without_interrupts(|| {
+        let registry = REGISTRY.lock();
+        registry.kernel.iter()
+            .chain(registry.stacks.iter().flatten())
+            .find(|stack| predicate(stack))
+            .copied()
+    })
+}
+
+/**
+ *  Finds the kernel stack, as the mapped pages around the current
+ *  stack pointer, the bootloader's guard page being the first
+ *  unmapped one beneath them.
+ */
+fn kernel_stack() -> Option<Stack> {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp,
+            options(nomem, nostack, preserves_flags));
+    }
+    let is_mapped = |addr: u64| VirtAddr::try_new(addr).ok()
+        .and_then(memory::translate_addr)
+        .is_some();
+    let mut bottom = rsp & !(PAGE_SIZE - 1);
+    if !is_mapped(bottom) {
+        return None;
+    }
+    let mut top = bottom + PAGE_SIZE;
+    while bottom >= PAGE_SIZE && is_mapped(bottom - PAGE_SIZE) {
+        bottom -= PAGE_SIZE;
+    }
+    while is_mapped(top) {
+        top += PAGE_SIZE;
+    }
+    Some(Stack {
+        name: "kernel",
+        bottom: VirtAddr::new(bottom),
+        top: VirtAddr::new(top),
+    })
+}
+
+/**
+ *  Maps every page of stack to a new frame, undoing
+ *  mappings already done if any of them fails.
+ */
+fn map_stack(stack: &Stack) -> Result<(), MapError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let pages = stack_pages(stack);
+    for page in pages {
+        if let Err(err) = unsafe { memory::map_page(page, flags) } {
+            unmap_pages(Page::range(pages.start, page))?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/**
+ *  Unmaps pages, freeing their frames.
+ */
+fn unmap_pages(pages: PageRange) -> Result<(), MapError> {
+    for page in pages {
+        unsafe {
+            let frame = memory::unmap_page(page)?;
+            memory::deallocate_frame(frame);
+        }
+    }
+    Ok(())
+}
+
+/// Range of stack's mapped pages.
+fn stack_pages(stack: &Stack) -> PageRange {
+    Page::range(
+        Page::containing_address(stack.bottom),
+        Page::containing_address(stack.top),
+    )
+}
+
+/**
+ *  Registers stack's guard page, named after it, for
+ *  page fault dispatching.
+ */
+fn register_guard(stack: &Stack) -> Result<(), page_fault::RegisterError> {
+    page_fault::register(stack.name, stack.guard_page().start_address(),
+        PAGE_SIZE, PageTableFlags::PRESENT, page_fault::guard)?;
+    Ok(())
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Allocates a stack, checking that its pages are mapped,
+ *  its guard page isn't and that the registry knows it.
+ */
+#[test_case]
+fn test_allocate() {
+    let stack = allocate("test", 2).unwrap();
+    assert_eq!(stack.top - stack.bottom, 2 * PAGE_SIZE);
+    assert!(memory::translate_addr(stack.bottom).is_some());
+    assert!(memory::translate_addr(stack.top - 1u64).is_some());
+    let guard_addr = stack.guard_page().start_address();
+    assert_eq!(memory::translate_addr(guard_addr), None);
+
+    assert_eq!(containing(stack.bottom).map(|s| s.name), Some("test"));
+    assert_eq!(overflowed(guard_addr + 8u64).map(|s| s.name), Some("test"));
+    assert!(overflowed(stack.bottom).is_none());
+}
+
+/**
+ *  Checks that kernel stack is known without any allocation.
+ */
+#[test_case]
+fn test_kernel_stack() {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp,
+            options(nomem, nostack, preserves_flags));
+    }
+    let stack = containing(VirtAddr::new(rsp)).unwrap();
+    assert_eq!(stack.name, "kernel");
+    let guard_addr = stack.guard_page().start_address();
+    assert_eq!(memory::translate_addr(guard_addr), None);
+    assert!(memory::translate_addr(stack.top - 1u64).is_some());
+}
@@ -0,0 +1,189 @@
+/*!
+ *  Kernel symbol table, resolving code addresses to function names.
+ *
+ *  Room for the table is reserved in the `.ksyms` section of the
+ *  kernel image, which is filled after linking by the `ksyms` host
+ *  tool (see `tools/ksyms`, run by the cargo runner before booting).
+ *  Table has the following layout, all integers being little endian:
+ *
+ *  | Offset           | Content                                    |
+ *  |------------------|--------------------------------------------|
+ *  | 0                | magic (`MOONSYMS`)                         |
+ *  | 8                | number of symbols (`u32`), plus padding    |
+ *  | 16               | entries sorted by address                  |
+ *  | 16 + 16 * count  | NUL-terminated demangled names             |
+ *
+ *  Each entry has a function's address (`u64`), its size (`u32`)
+ *  and the offset of its name (`u32`) from names' start.
+ */
+
+use core::{ptr, str};
+
+/// Bytes reserved for entries and names.
+pub const CAPACITY: usize = 512 * 1024;
+
+/// Identifies the table, so that the tool can check it's found.
+const MAGIC: [u8; 8] = *b"MOONSYMS";
+
+/// Size of each table entry in bytes.
+const ENTRY_SIZE: usize = 16;
+
+/**
+ *  Function symbol.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    /// Demangled name, without hash.
+    pub name: &'static str,
+
+    /// Starting address.
+    pub address: u64,
+
+    /// Size in bytes.
+    pub size: u64,
+}
+
+/**
+ *  Finds the function containing given address.
+ *
+ *  Returns its symbol and the offset of `addr` into it,
+ *  or `None` if no function (or no table) is found.
+ */
+pub fn resolve(addr: u64) -> Option<(Symbol, u64)> {
+    let table = table();
+
+    // Binary searches for the last entry starting at or before `addr`
+    let (mut low, mut high) = (0, table.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if table.entry(mid).address <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+    let symbol = table.entry(low - 1);
+    let offset = addr - symbol.address;
+    if offset >= symbol.size {
+        return None;
+    }
+    Some((symbol, offset))
+}
+
+/**
+ *  Gets number of symbols in table (zero if it was never filled).
+ */
+pub fn count() -> usize {
+    table().len()
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Symbol table, as written by the `ksyms` tool.
+ */
+#[repr(C)]
+struct SymbolTable {
+    /// Always [`MAGIC`].
+    magic: [u8; 8],
+
+    /// Number of entries.
+    count: u32,
+
+    /// Keeps `data` 16-byte aligned.
+    _padding: u32,
+
+    /// Entries followed by names.
+    data: [u8; CAPACITY],
+}
+
+/**
+ *  The table itself, empty until filled after linking.
+ */
+#[used]
+#[link_section = ".ksyms"]
+static mut SYMBOL_TABLE: SymbolTable = SymbolTable {
+    magic: MAGIC,
+    count: 0,
+    _padding: 0,
+    data: [0; CAPACITY],
+};
+
+/**
+ *  Gets a reference to the table.
+ *
+ *  As contents are changed after compilation, table's address is
+ *  passed through an empty `asm!` block, so that the compiler can't
+ *  assume the initial (empty) values.
+ */
+fn table() -> &'static SymbolTable {
+    let mut table = unsafe { ptr::addr_of!(SYMBOL_TABLE) };
+    unsafe {
+        asm!("/* {} */", inout(reg) table,
+            options(pure, nomem, nostack, preserves_flags));
+        &*table
+    }
+}
+
+impl SymbolTable {
+    /**
+     *  Gets number of entries, bounded by table's capacity.
+     */
+    fn len(&self) -> usize {
+        (self.count as usize).min(CAPACITY / ENTRY_SIZE)
+    }
+
+    /**
+     *  Decodes `index`-th entry, which must be less than `count`.
+     */
+    fn entry(&'static self, index: usize) -> Symbol {
+        let start = index * ENTRY_SIZE;
+        let entry = &self.data[start..start + ENTRY_SIZE];
+        let address = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+        let size = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let name_offset =
+            u32::from_le_bytes(entry[12..16].try_into().unwrap());
+        Symbol {
+            name: self.name(name_offset as usize),
+            address,
+            size: size as u64,
+        }
+    }
+
+    /**
+     *  Gets the NUL-terminated name starting at `offset` of names.
+     */
+    fn name(&'static self, offset: usize) -> &'static str {
+        let start = (self.len() * ENTRY_SIZE + offset).min(CAPACITY);
+        let bytes = &self.data[start..];
+        let len = bytes.iter()
+            .position(|&b| b == 0)
+            .unwrap_or(bytes.len());
+        str::from_utf8(&bytes[..len]).unwrap_or("<invalid name>")
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Resolves an address inside a kernel function.
+ */
+#[test_case]
+fn test_resolve() {
+    // Returns a distinctive value, so it isn't merged with another
+    #[inline(never)]
+    fn known_function() -> u64 {
+        0x6b73_796d
+    }
+
+    assert!(count() > 0, "symbol table wasn't filled by `ksyms`");
+    let addr = known_function as fn() -> u64 as u64;
+    let (symbol, offset) = resolve(addr).expect("symbol not found");
+    assert_eq!(symbol.address, addr);
+    assert_eq!(offset, 0);
+    assert_eq!(symbol.name, "moon_os::symbols::test_resolve::known_function");
+    assert!(resolve(0).is_none());
+}
@@ -0,0 +1,77 @@
+/*!
+ *  Cooperative multitasking, through async/await.
+ *
+ *  A [`Task`] wraps a future, which an executor polls until it
+ *  completes. Tasks give CPU back at every `.await` not ready yet
+ *  (never being preempted), so they must not block.
+ *
+ *  Two executors are available:
+ *
+ *  - [`simple_executor::SimpleExecutor`]: polls every task in turn,
+ *    over and over, until they all complete;
+ *  - [`executor::Executor`]: only polls tasks whose wakers were
+ *    woken, halting CPU whilst there are none.
+ */
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use alloc::boxed::Box;
+
+pub mod executor;
+pub mod keyboard;
+pub mod mouse;
+pub mod simple_executor;
+
+/**
+ *  Asynchronous task, run by an executor.
+ */
+pub struct Task {
+    /// Unique identifier.
+    id: TaskId,
+
+    /// Task's work, pinned to the heap as it may be self-referential.
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    /**
+     *  Creates a task running `future`.
+     */
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    /**
+     *  Gets task's identifier.
+     */
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /**
+     *  Polls task's future, resuming it.
+     */
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/**
+ *  Identifies a task, never reused.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// Takes next identifier.
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,238 @@
+/*!
+ *  Waker-based executor.
+ *
+ *  Tasks are only polled when ready to make progress, that is, when
+ *  spawned or once their waker is woken (usually by an interrupt
+ *  handler). Woken tasks are queued by id; whilst the queue is empty,
+ *  CPU is halted until the next interrupt.
+ *
+ *  Wakers may be woken from interrupt handlers, so the queue is only
+ *  touched with interrupts disabled and never allocates after creation.
+ */
+
+use core::task::{Context, Poll, Waker};
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use super::{Task, TaskId};
+
+/// Maximum number of tasks in an executor.
+pub const MAX_TASKS: usize = 256;
+
+/**
+ *  Executor polling tasks on wake-ups.
+ */
+pub struct Executor {
+    /// Unfinished tasks.
+    tasks: BTreeMap<TaskId, Task>,
+
+    /// Ids of tasks ready to be polled.
+    task_queue: Arc<TaskQueue>,
+
+    /// Waker of each task, so that it's only created once.
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    /**
+     *  Creates an executor without tasks.
+     */
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(TaskQueue::new()),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /**
+     *  Adds a task, to be polled once running.
+     *
+     *  Panics if there are already `MAX_TASKS` tasks.
+     */
+    pub fn spawn(&mut self, task: Task) {
+        assert!(self.tasks.len() < MAX_TASKS, "too many tasks");
+        let id = task.id;
+        self.tasks.insert(id, task);
+        self.task_queue.push(id);
+    }
+
+    /**
+     *  Runs tasks forever, halting whenever none is ready.
+     */
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /**
+     *  Polls queued tasks until none is left, dropping finished ones.
+     */
+    pub(super) fn run_ready_tasks(&mut self) {
+        // Splits borrows, as task and waker are used together
+        let Self { tasks, task_queue, waker_cache } = self;
+        while let Some(id) = task_queue.pop() {
+            // Task may have finished since it was woken
+            let task = match tasks.get_mut(&id) {
+                Some(task) => task,
+                None => continue,
+            };
+            let waker = waker_cache.entry(id).or_insert_with(|| {
+                Waker::from(Arc::new(TaskWaker {
+                    id,
+                    task_queue: task_queue.clone(),
+                }))
+            });
+            let mut context = Context::from_waker(waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                tasks.remove(&id);
+                waker_cache.remove(&id);
+            }
+        }
+    }
+
+    /**
+     *  Halts CPU until next interrupt, unless a task is ready.
+     */
+    fn sleep_if_idle(&self) {
+        // Checking and halting can't be split by a waking interrupt
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Ids of tasks ready to be polled, each queued at most once.
+ */
+struct TaskQueue {
+    /// Queued ids, with room for `MAX_TASKS` reserved beforehand.
+    ids: spin::Mutex<VecDeque<TaskId>>,
+}
+
+impl TaskQueue {
+    /// Creates an empty queue.
+    fn new() -> Self {
+        TaskQueue {
+            ids: spin::Mutex::new(VecDeque::with_capacity(MAX_TASKS)),
+        }
+    }
+
+    /// Queues an id, unless already queued.
+    fn push(&self, id: TaskId) {
+        without_interrupts(|| {
+            let mut ids = self.ids.lock();
+            if !ids.contains(&id) {
+                // Growing would allocate, maybe from an interrupt handler
+                assert!(ids.len() < ids.capacity(), "task queue full");
+                ids.push_back(id);
+            }
+        });
+    }
+
+    /// Takes the oldest id.
+    fn pop(&self) -> Option<TaskId> {
+        without_interrupts(|| self.ids.lock().pop_front())
+    }
+
+    /// If no id is queued.
+    fn is_empty(&self) -> bool {
+        without_interrupts(|| self.ids.lock().is_empty())
+    }
+}
+
+/**
+ *  Waker queueing its task on wake-ups.
+ */
+struct TaskWaker {
+    /// Task to wake.
+    id: TaskId,
+
+    /// Executor's queue.
+    task_queue: Arc<TaskQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.task_queue.push(self.id);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.task_queue.push(self.id);
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+#[cfg(test)]
+use core::{future::Future, pin::Pin};
+
+/**
+ *  Future pending once, waking its task right away.
+ */
+#[cfg(test)]
+struct YieldNow(bool);
+
+#[cfg(test)]
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/**
+ *  Runs a task yielding a few times and a task never woken,
+ *  which must be left pending.
+ */
+#[test_case]
+fn test_run_ready_tasks() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static YIELDS: AtomicUsize = AtomicUsize::new(0);
+    async fn yielding() {
+        for _ in 0..3 {
+            YieldNow(false).await;
+            YIELDS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    struct Never;
+    impl Future for Never {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, _context: &mut Context) -> Poll<()> {
+            Poll::Pending
+        }
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(yielding()));
+    executor.spawn(Task::new(Never));
+    executor.run_ready_tasks();
+    assert_eq!(YIELDS.load(Ordering::Relaxed), 3);
+    assert_eq!(executor.tasks.len(), 1);
+    assert_eq!(executor.waker_cache.len(), 1);
+    assert!(executor.task_queue.is_empty());
+}
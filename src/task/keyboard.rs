@@ -0,0 +1,165 @@
+/*!
+ *  Asynchronous keyboard input.
+ *
+ *  Keyboard interrupt handler, after queuing a scancode to
+ *  [`crate::keyboard`], wakes the task awaiting on the
+ *  [`ScancodeStream`], if any.
+ */
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use pc_keyboard::DecodedKey;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::keyboard;
+use crate::println;
+
+/**
+ *  Prints every key pressed, forever.
+ */
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    loop {
+        let scancode = scancodes.next_scancode().await;
+        if let Some(key) = keyboard::decode(scancode) {
+            print_key(key);
+        }
+    }
+}
+
+/**
+ *  Stream of scancodes from keyboard.
+ *
+ *  Scancodes are taken from the same queue as `keyboard::read_key`,
+ *  so only one of them should be reading at a time.
+ */
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    /**
+     *  Creates a stream of keyboard scancodes.
+     */
+    pub fn new() -> Self {
+        ScancodeStream { _private: () }
+    }
+
+    /**
+     *  Waits for next scancode.
+     */
+    pub fn next_scancode(&mut self) -> NextScancode<'_> {
+        NextScancode { stream: self }
+    }
+
+    /**
+     *  Takes next scancode if there's one, or else registers
+     *  `context`'s waker to be woken when one comes.
+     */
+    pub fn poll_next(&mut self, context: &mut Context) -> Poll<u8> {
+        if let Some(scancode) = keyboard::pop_scancode() {
+            return Poll::Ready(scancode);
+        }
+        without_interrupts(|| {
+            let mut waker = WAKER.lock();
+            match waker.as_ref() {
+                Some(registered) if registered.will_wake(context.waker()) => {}
+                _ => *waker = Some(context.waker().clone()),
+            }
+        });
+        // Checks again, as one may have come before registering
+        match keyboard::pop_scancode() {
+            Some(scancode) => Poll::Ready(scancode),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ *  Future of `ScancodeStream::next_scancode`.
+ */
+pub struct NextScancode<'a> {
+    /// Stream polled.
+    stream: &'a mut ScancodeStream,
+}
+
+impl Future for NextScancode<'_> {
+    type Output = u8;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<u8> {
+        self.stream.poll_next(context)
+    }
+}
+
+/**
+ *  Wakes the task awaiting a scancode. Called by the keyboard
+ *  interrupt handler only, after queuing one.
+ */
+pub(crate) fn wake() {
+    // Waker is kept, so that dropping it never frees memory in here
+    if let Some(waker) = WAKER.lock().as_ref() {
+        waker.wake_by_ref();
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Waker of the task awaiting a scancode.
+static WAKER: spin::Mutex<Option<Waker>> = spin::Mutex::new(None);
+
+/**
+ *  Prints a key read from keyboard.
+ */
+fn print_key(key: DecodedKey) {
+    match key {
+        DecodedKey::Unicode(character) => {
+            // Prints char normally if in ASCII printable range;
+            // otherwise, its code is printed instead.
+            if (' '..='~').contains(&character) {
+                println!("{}", character);
+            } else {
+                println!("{:?}", character);
+            }
+        }
+        DecodedKey::RawKey(key) => {
+            println!("{:?}", key);
+        }
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Awaits a scancode, which must wake the task once queued.
+ */
+#[test_case]
+fn test_scancode_stream() {
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    use super::{executor::Executor, Task};
+
+    static SCANCODE: AtomicU8 = AtomicU8::new(0);
+    async fn read_scancode() {
+        let scancode = ScancodeStream::new().next_scancode().await;
+        SCANCODE.store(scancode, Ordering::Relaxed);
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(read_scancode()));
+    executor.run_ready_tasks();
+    assert_eq!(SCANCODE.load(Ordering::Relaxed), 0);
+    without_interrupts(|| {
+        keyboard::push_scancode(0x1e);
+        wake();
+    });
+    executor.run_ready_tasks();
+    assert_eq!(SCANCODE.load(Ordering::Relaxed), 0x1e);
+}
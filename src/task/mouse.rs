@@ -0,0 +1,135 @@
+/*!
+ *  Asynchronous mouse input.
+ *
+ *  Mouse interrupt handler, after publishing events to
+ *  [`crate::mouse`], wakes the task awaiting on the
+ *  [`MouseEventStream`], if any.
+ */
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::mouse::{self, MouseEvent};
+use crate::println;
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/**
+ *  Moves a pointer around the screen following mouse movement,
+ *  printing every other event, forever.
+ */
+pub async fn draw_pointer() {
+    let width = (BUFFER_WIDTH * COUNTS_PER_COLUMN) as i32;
+    let height = (BUFFER_HEIGHT * COUNTS_PER_ROW) as i32;
+    let (mut x, mut y) = (width / 2, height / 2);
+    let mut events = MouseEventStream::new();
+    loop {
+        let row = y as usize / COUNTS_PER_ROW;
+        let col = x as usize / COUNTS_PER_COLUMN;
+        vga_buffer::set_pointer(Some((row, col)));
+        match events.next_event().await {
+            // Screen rows grow downwards, unlike mouse's
+            MouseEvent::Move { dx, dy } => {
+                x = (x + dx as i32).clamp(0, width - 1);
+                y = (y - dy as i32).clamp(0, height - 1);
+            }
+            event => println!("{:?}", event),
+        }
+    }
+}
+
+/**
+ *  Stream of mouse events.
+ *
+ *  Events are taken from the same queue as `mouse::read_event`,
+ *  so each event goes to only one of them.
+ */
+pub struct MouseEventStream {
+    _private: (),
+}
+
+impl MouseEventStream {
+    /**
+     *  Creates a stream of mouse events.
+     */
+    pub fn new() -> Self {
+        MouseEventStream { _private: () }
+    }
+
+    /**
+     *  Waits for next event.
+     */
+    pub fn next_event(&mut self) -> NextEvent<'_> {
+        NextEvent { stream: self }
+    }
+
+    /**
+     *  Takes next event if there's one, or else registers
+     *  `context`'s waker to be woken when one comes.
+     */
+    pub fn poll_next(&mut self, context: &mut Context) -> Poll<MouseEvent> {
+        if let Some(event) = mouse::try_read_event() {
+            return Poll::Ready(event);
+        }
+        without_interrupts(|| {
+            let mut waker = WAKER.lock();
+            match waker.as_ref() {
+                Some(registered) if registered.will_wake(context.waker()) => {}
+                _ => *waker = Some(context.waker().clone()),
+            }
+        });
+        // Checks again, as one may have come before registering
+        match mouse::try_read_event() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Default for MouseEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ *  Future of `MouseEventStream::next_event`.
+ */
+pub struct NextEvent<'a> {
+    /// Stream polled.
+    stream: &'a mut MouseEventStream,
+}
+
+impl Future for NextEvent<'_> {
+    type Output = MouseEvent;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context)
+        -> Poll<MouseEvent>
+    {
+        self.stream.poll_next(context)
+    }
+}
+
+/**
+ *  Wakes the task awaiting an event. Called by the mouse
+ *  interrupt handler only, after publishing some.
+ */
+pub(crate) fn wake() {
+    // Waker is kept, so that dropping it never frees memory in here
+    if let Some(waker) = WAKER.lock().as_ref() {
+        waker.wake_by_ref();
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Mouse counts moving pointer by a column.
+const COUNTS_PER_COLUMN: usize = 8;
+
+/// Mouse counts moving pointer by a row.
+const COUNTS_PER_ROW: usize = 16;
+
+/// Waker of the task awaiting an event.
+static WAKER: spin::Mutex<Option<Waker>> = spin::Mutex::new(None);
@@ -0,0 +1,103 @@
+/*!
+ *  Simple executor.
+ *
+ *  Polls queued tasks round-robin, requeuing unfinished ones, so it
+ *  keeps CPU busy even when no task can make progress. Wakers do
+ *  nothing, as every task is polled again anyway.
+ */
+
+use core::ptr;
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+use alloc::collections::VecDeque;
+
+use super::Task;
+
+/**
+ *  Executor busy-polling every task.
+ */
+pub struct SimpleExecutor {
+    /// Tasks to poll, in order.
+    task_queue: VecDeque<Task>,
+}
+
+impl SimpleExecutor {
+    /**
+     *  Creates an executor without tasks.
+     */
+    pub fn new() -> Self {
+        SimpleExecutor {
+            task_queue: VecDeque::new(),
+        }
+    }
+
+    /**
+     *  Queues a task.
+     */
+    pub fn spawn(&mut self, task: Task) {
+        self.task_queue.push_back(task);
+    }
+
+    /**
+     *  Runs tasks until they all complete.
+     */
+    pub fn run(&mut self) {
+        while let Some(mut task) = self.task_queue.pop_front() {
+            let waker = dummy_waker();
+            let mut context = Context::from_waker(&waker);
+            if task.poll(&mut context).is_pending() {
+                self.task_queue.push_back(task);
+            }
+        }
+    }
+}
+
+impl Default for SimpleExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Creates a waker which does nothing.
+ */
+fn dummy_waker() -> Waker {
+    fn no_op(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker {
+        dummy_raw_waker()
+    }
+    fn dummy_raw_waker() -> RawWaker {
+        static VTABLE: RawWakerVTable =
+            RawWakerVTable::new(clone, no_op, no_op, no_op);
+        RawWaker::new(ptr::null(), &VTABLE)
+    }
+    unsafe { Waker::from_raw(dummy_raw_waker()) }
+}
+
+/*---------------------------------------------------------------------------*/
+
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/**
+ *  Runs tasks awaiting other futures, which must all complete.
+ */
+#[test_case]
+fn test_run() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    async fn number() -> usize {
+        21
+    }
+    async fn add_number() {
+        DONE.fetch_add(number().await, Ordering::Relaxed);
+    }
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(add_number()));
+    executor.spawn(Task::new(add_number()));
+    executor.run();
+    assert_eq!(DONE.load(Ordering::Relaxed), 42);
+    assert!(executor.task_queue.is_empty());
+}
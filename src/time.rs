@@ -0,0 +1,327 @@
+/*!
+ *  Kernel tick, monotonic uptime clock and high-resolution timestamps.
+ *
+ *  A tick source raises the timer interrupt at a configurable
+ *  frequency, each interrupt counting one tick and adding its period
+ *  to the uptime. The local APIC timer is preferred when the APIC is
+ *  in use (after being calibrated against HPET or PIT channel 2),
+ *  with the PIT's channel 0 taking its place otherwise. Built with the
+ *  `hpet_tick` feature, HPET's timer 0 is chosen instead, if present.
+ *
+ *  Timestamps ([`Instant`]) are read from the TSC instead, with
+ *  nanosecond resolution, falling back to the uptime when there's no
+ *  TSC. Reading them takes no lock, so they're usable anywhere,
+ *  interrupt handlers included.
+ */
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::apic;
+use crate::hpet::{self, Route, TimerMode};
+use crate::pit;
+use crate::tsc;
+
+/// Tick frequency set on `init`, in Hz.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+/**
+ *  Hardware timers able to drive the kernel tick.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    /// PIT channel 0, on ISA IRQ 0.
+    Pit,
+
+    /// Local APIC timer, in periodic mode.
+    ApicTimer,
+
+    /// HPET timer 0, in legacy replacement mode (taking over IRQ 0).
+    Hpet,
+}
+
+/**
+ *  Chooses tick source and starts it at `DEFAULT_FREQUENCY`.
+ *
+ *  Called by `interrupts::init` once the interrupt controller is set,
+ *  with the `vector` the APIC timer (if chosen) must raise.
+ */
+pub fn init(vector: u8) {
+    let hpet_enabled = hpet::init().is_ok();
+    if tsc::calibrate().is_some() {
+        BOOT_TSC.store(tsc::read(), Ordering::Relaxed);
+    }
+    let apic_counts_per_second = calibrate_apic_timer();
+    let source = if cfg!(feature = "hpet_tick") && hpet_enabled {
+        TickSource::Hpet
+    } else if apic_counts_per_second.is_some() {
+        TickSource::ApicTimer
+    } else {
+        TickSource::Pit
+    };
+    without_interrupts(|| {
+        let mut clock = CLOCK.lock();
+        clock.source = source;
+        clock.vector = vector;
+        clock.apic_counts_per_second = apic_counts_per_second.unwrap_or(0);
+    });
+    set_frequency(DEFAULT_FREQUENCY);
+    if source == TickSource::ApicTimer {
+        // PIT isn't needed anymore, so its IRQ is masked
+        let _ = apic::mask_irq(apic::isa_irq_gsi(0));
+    }
+}
+
+/**
+ *  Reprograms tick source to (about) `hz` ticks a second.
+ *
+ *  Returns the actual frequency, as close as the source allows.
+ */
+pub fn set_frequency(hz: u32) -> u32 {
+    without_interrupts(|| {
+        let mut clock = CLOCK.lock();
+        let frequency = match clock.source {
+            TickSource::Pit => pit::set_frequency(hz),
+            TickSource::ApicTimer => {
+                let counts_per_second = clock.apic_counts_per_second;
+                let initial_count = (counts_per_second / hz.max(1) as u64)
+                    .clamp(1, u32::MAX as u64);
+                apic::start_timer(clock.vector, initial_count as u32, true)
+                    .expect("APIC timer unavailable");
+                (counts_per_second / initial_count) as u32
+            }
+            TickSource::Hpet => {
+                let period = Duration::from_nanos(
+                    1_000_000_000 / hz.max(1) as u64);
+                let period = hpet::start_timer(
+                    0, TimerMode::Periodic, period, Route::Legacy)
+                    .expect("HPET timer unavailable");
+                (1_000_000_000 / period.as_nanos().max(1)) as u32
+            }
+        };
+        clock.frequency = frequency;
+        TICK_PERIOD_NANOS.store(
+            1_000_000_000 / frequency.max(1) as u64, Ordering::Relaxed);
+        frequency
+    })
+}
+
+/**
+ *  Gets the tick source chosen on `init`.
+ */
+pub fn tick_source() -> TickSource {
+    without_interrupts(|| CLOCK.lock().source)
+}
+
+/**
+ *  Gets the current tick frequency, in Hz.
+ */
+pub fn frequency() -> u32 {
+    without_interrupts(|| CLOCK.lock().frequency)
+}
+
+/**
+ *  Gets the number of ticks since `init`.
+ */
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/**
+ *  Gets the time elapsed since `init`, at tick granularity.
+ */
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::Relaxed))
+}
+
+/**
+ *  Gets the current timestamp.
+ */
+pub fn now() -> Instant {
+    let frequency = tsc::frequency();
+    if frequency == 0 {
+        return Instant(uptime().as_nanos() as u64);
+    }
+    let elapsed = tsc::read().wrapping_sub(BOOT_TSC.load(Ordering::Relaxed));
+    Instant((elapsed as u128 * 1_000_000_000 / frequency as u128) as u64)
+}
+
+/**
+ *  Point in time, measured in nanoseconds since boot.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Nanoseconds since boot (since `init`, strictly speaking).
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed since `earlier`, zero if it's actually later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time elapsed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        now().duration_since(*self)
+    }
+
+    /// Instant `duration` later, if it can be represented.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Instant `duration` later, saturating at the latest one.
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant(u64::MAX))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/**
+ *  Counts a tick. Called by the timer interrupt handler only.
+ */
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let period = TICK_PERIOD_NANOS.load(Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(period, Ordering::Relaxed);
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Time spent counting APIC timer's decrements on calibration.
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+/**
+ *  Tick source settings.
+ */
+struct Clock {
+    /// Timer raising ticks.
+    source: TickSource,
+
+    /// Vector raised on each tick.
+    vector: u8,
+
+    /// Current tick frequency in Hz.
+    frequency: u32,
+
+    /// APIC timer decrements per second (zero if not calibrated).
+    apic_counts_per_second: u64,
+}
+
+/// Tick source settings, set up on `init`.
+static CLOCK: spin::Mutex<Clock> = spin::Mutex::new(Clock {
+    source: TickSource::Pit,
+    vector: 0,
+    frequency: 0,
+    apic_counts_per_second: 0,
+});
+
+/// Ticks counted since `init`.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds elapsed since `init`, summed up on each tick.
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Current tick period in nanoseconds.
+static TICK_PERIOD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value on `init`, from which timestamps are counted.
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/**
+ *  Busy-waits for `duration` on HPET, or on PIT if there's no HPET,
+ *  for calibrating other timers.
+ */
+pub(crate) fn reference_wait(duration: Duration) {
+    if hpet::wait(duration).is_err() {
+        pit::wait(duration);
+    }
+}
+
+/**
+ *  Measures APIC timer's decrements per second, if APIC is in use.
+ */
+fn calibrate_apic_timer() -> Option<u64> {
+    if !apic::is_enabled() {
+        return None;
+    }
+    // Counting down from the maximum doesn't reach zero meanwhile,
+    // so the (spurious) vector is never raised
+    apic::start_timer(apic::SPURIOUS_VECTOR, u32::MAX, false).ok()?;
+    reference_wait(CALIBRATION_TIME);
+    let elapsed = u32::MAX - apic::timer_count()?;
+    apic::stop_timer().ok()?;
+    let counts_per_second =
+        elapsed as u64 * 1_000_000 / CALIBRATION_TIME.as_micros() as u64;
+    Some(counts_per_second).filter(|&counts| counts > 0)
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Busy-waits on PIT channel 2 (independent from the tick source),
+ *  checking that ticks advance at the expected rate.
+ */
+#[test_case]
+fn test_tick_rate() {
+    const WAIT: Duration = Duration::from_millis(100);
+    let (start_ticks, start_uptime) = (ticks(), uptime());
+    pit::wait(WAIT);
+    let elapsed_ticks = ticks() - start_ticks;
+    let elapsed_uptime = uptime() - start_uptime;
+
+    let expected = frequency() as u64 * WAIT.as_millis() as u64 / 1000;
+    assert!(elapsed_ticks >= expected / 2 && elapsed_ticks <= expected * 2,
+        "{} ticks elapsed, expected about {}", elapsed_ticks, expected);
+    assert!(elapsed_uptime >= WAIT / 2 && elapsed_uptime <= WAIT * 2);
+}
+
+/**
+ *  Checks that timestamps are monotonic, have sub-tick resolution
+ *  and agree with PIT on elapsed time.
+ */
+#[test_case]
+fn test_now() {
+    const WAIT: Duration = Duration::from_millis(20);
+    let start = now();
+    let next = now();
+    assert!(next >= start);
+    if tsc::frequency() == 0 {
+        return;
+    }
+    assert!(next - start < Duration::from_millis(1));
+    pit::wait(WAIT);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= WAIT * 3 / 4 && elapsed <= WAIT * 2,
+        "{:?} elapsed, expected about {:?}", elapsed, WAIT);
+}
+
+/**
+ *  Adds durations too long for an instant, which must saturate.
+ */
+#[test_case]
+fn test_instant_overflow() {
+    let start = Instant(1);
+    assert_eq!(start.checked_add(Duration::from_nanos(2)), Some(Instant(3)));
+    assert_eq!(start.checked_add(Duration::from_nanos(u64::MAX)), None);
+    assert_eq!(start.checked_add(Duration::MAX), None);
+    assert_eq!(start + Duration::MAX, Instant(u64::MAX));
+}
@@ -0,0 +1,385 @@
+/*!
+ *  Timer wheel, running callbacks on deadlines, and sleeping.
+ *
+ *  Timers are kept in a hashed wheel of `WHEEL_SLOTS` slots, each
+ *  holding the timers whose deadline tick falls on it (modulo the
+ *  number of slots). On every tick only the current slot is looked
+ *  at, firing timers which are due and leaving the others there for
+ *  a later round, so ticks take constant time however many timers
+ *  are pending.
+ *
+ *  Callbacks run from the timer interrupt handler, so they must be
+ *  short and must not block (nor wait for locks held elsewhere
+ *  with interrupts enabled).
+ */
+
+use core::time::Duration;
+
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::time;
+
+/// Maximum number of pending timers.
+pub const MAX_TIMERS: usize = 64;
+
+/// Number of wheel slots (ticks covered in one wheel round).
+pub const WHEEL_SLOTS: usize = 256;
+
+/**
+ *  Runs when a timer fires, given its id.
+ */
+pub type Callback = fn(id: TimerId);
+
+/**
+ *  Schedules `callback` to run at uptime `deadline` (rounded up
+ *  to the next tick) and then, if `period` is given, every `period`.
+ */
+pub fn schedule(deadline: Duration, period: Option<Duration>,
+    callback: Callback) -> Result<TimerId, TimerError>
+{
+    let frequency = time::frequency();
+    if frequency == 0 {
+        return Err(TimerError::NotRunning);
+    }
+    let period = match period {
+        Some(period) => match to_ticks(period, frequency) {
+            0 => return Err(TimerError::ZeroPeriod),
+            ticks => ticks,
+        },
+        None => 0,
+    };
+    without_interrupts(|| {
+        let delay = deadline.saturating_sub(time::uptime());
+        let deadline = time::ticks().saturating_add(to_ticks(delay, frequency));
+        WHEEL.lock().insert(deadline, period, callback)
+    })
+}
+
+/**
+ *  Schedules `callback` to run once, after `delay`.
+ */
+pub fn after(delay: Duration, callback: Callback)
+    -> Result<TimerId, TimerError>
+{
+    schedule(time::uptime().saturating_add(delay), None, callback)
+}
+
+/**
+ *  Schedules `callback` to run every `period`, starting after one.
+ */
+pub fn every(period: Duration, callback: Callback)
+    -> Result<TimerId, TimerError>
+{
+    schedule(time::uptime().saturating_add(period), Some(period), callback)
+}
+
+/**
+ *  Cancels a pending timer, so that it never fires again.
+ *
+ *  Returns `false` if it had already fired (if one-shot)
+ *  or been cancelled.
+ */
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| WHEEL.lock().remove(id))
+}
+
+/**
+ *  Halts CPU for (at least) `duration`.
+ */
+pub fn sleep(duration: Duration) {
+    sleep_until(time::uptime().saturating_add(duration));
+}
+
+/**
+ *  Halts CPU until uptime reaches `deadline`, waking up on each
+ *  interrupt to check it.
+ *
+ *  Panics if interrupts are disabled, as no tick would ever wake it.
+ */
+pub fn sleep_until(deadline: Duration) {
+    assert!(interrupts::are_enabled(), "sleeping with interrupts disabled");
+    loop {
+        // Checking and halting can't be split by the waking interrupt
+        interrupts::disable();
+        if time::uptime() >= deadline {
+            interrupts::enable();
+            return;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/**
+ *  Identifies a scheduled timer.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    /// Index of timer's entry.
+    index: usize,
+
+    /// Distinguishes timers reusing the same entry.
+    generation: u32,
+}
+
+/**
+ *  Errors returned on timer scheduling.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// All `MAX_TIMERS` entries are taken.
+    TooManyTimers,
+
+    /// Period is shorter than a tick.
+    ZeroPeriod,
+
+    /// Tick source isn't started yet.
+    NotRunning,
+}
+
+/**
+ *  Fires due timers. Called by the timer interrupt handler only,
+ *  after counting the tick.
+ */
+pub(crate) fn tick() {
+    let mut due = [None; MAX_TIMERS];
+    WHEEL.lock().advance(time::ticks(), &mut due);
+    for (id, callback) in due.iter().flatten() {
+        callback(*id);
+    }
+}
+
+/*---------------------------------------------------------------------------*/
+
+/**
+ *  Pending timer.
+ */
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Tick on which timer fires.
+    deadline: u64,
+
+    /// Ticks between firings (zero if one-shot).
+    period: u64,
+
+    /// Runs on firing.
+    callback: Callback,
+
+    /// Next entry in the same slot.
+    next: Option<usize>,
+}
+
+/**
+ *  Timers, linked into wheel slots.
+ */
+struct Wheel {
+    /// Pending timers.
+    entries: [Option<Entry>; MAX_TIMERS],
+
+    /// Generation of each entry, bumped when it's freed.
+    generations: [u32; MAX_TIMERS],
+
+    /// First entry of each slot.
+    slots: [Option<usize>; WHEEL_SLOTS],
+
+    /// Last tick processed.
+    current: u64,
+}
+
+/// Timer wheel.
+static WHEEL: spin::Mutex<Wheel> = spin::Mutex::new(Wheel {
+    entries: [None; MAX_TIMERS],
+    generations: [0; MAX_TIMERS],
+    slots: [None; WHEEL_SLOTS],
+    current: 0,
+});
+
+impl Wheel {
+    /**
+     *  Adds a timer, firing on the next tick if already due.
+     */
+    fn insert(&mut self, deadline: u64, period: u64, callback: Callback)
+        -> Result<TimerId, TimerError>
+    {
+        let index = self.entries.iter()
+            .position(|entry| entry.is_none())
+            .ok_or(TimerError::TooManyTimers)?;
+        let deadline = deadline.max(self.current + 1);
+        self.entries[index] = Some(Entry {
+            deadline,
+            period,
+            callback,
+            next: None,
+        });
+        self.link(index);
+        Ok(TimerId { index, generation: self.generations[index] })
+    }
+
+    /**
+     *  Removes a timer, if still pending.
+     */
+    fn remove(&mut self, id: TimerId) -> bool {
+        if self.generations[id.index] != id.generation {
+            return false;
+        }
+        let entry = match self.entries[id.index] {
+            Some(entry) => entry,
+            None => return false,
+        };
+        // Finds whatever points to entry in its slot, then skips it
+        let slot = slot(entry.deadline);
+        if self.slots[slot] == Some(id.index) {
+            self.slots[slot] = entry.next;
+        } else {
+            let mut previous = self.slots[slot];
+            while let Some(index) = previous {
+                let previous_entry = self.entries[index].as_mut().unwrap();
+                if previous_entry.next == Some(id.index) {
+                    previous_entry.next = entry.next;
+                    break;
+                }
+                previous = previous_entry.next;
+            }
+        }
+        self.free(id.index);
+        true
+    }
+
+    /**
+     *  Processes every tick up to `now`, collecting due timers into
+     *  `due` and rescheduling periodic ones.
+     */
+    fn advance(&mut self, now: u64,
+        due: &mut [Option<(TimerId, Callback)>; MAX_TIMERS])
+    {
+        let mut count = 0;
+        while self.current < now {
+            self.current += 1;
+            let slot = slot(self.current);
+            let mut next = self.slots[slot].take();
+            while let Some(index) = next {
+                let mut entry = self.entries[index].unwrap();
+                next = entry.next;
+                // Due on a later round (or no room left, if ticks were
+                // missed, in which case it fires on the next round)
+                if entry.deadline > self.current || count == MAX_TIMERS {
+                    self.link(index);
+                    continue;
+                }
+                let id = TimerId { index, generation: self.generations[index] };
+                due[count] = Some((id, entry.callback));
+                count += 1;
+                if entry.period == 0 {
+                    self.free(index);
+                } else {
+                    entry.deadline =
+                        entry.deadline.saturating_add(entry.period);
+                    self.entries[index] = Some(entry);
+                    self.link(index);
+                }
+            }
+        }
+    }
+
+    /**
+     *  Pushes entry onto its deadline's slot.
+     */
+    fn link(&mut self, index: usize) {
+        let entry = self.entries[index].as_mut().unwrap();
+        let slot = slot(entry.deadline);
+        entry.next = self.slots[slot];
+        self.slots[slot] = Some(index);
+    }
+
+    /**
+     *  Frees an (already unlinked) entry, invalidating its ids.
+     */
+    fn free(&mut self, index: usize) {
+        self.entries[index] = None;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+    }
+}
+
+/**
+ *  Gets the slot of a tick.
+ */
+fn slot(tick: u64) -> usize {
+    (tick % WHEEL_SLOTS as u64) as usize
+}
+
+/**
+ *  Converts a duration to ticks at `frequency`, rounding up.
+ */
+fn to_ticks(duration: Duration, frequency: u32) -> u64 {
+    let nanos = duration.as_nanos() * frequency as u128;
+    u64::try_from((nanos + 999_999_999) / 1_000_000_000).unwrap_or(u64::MAX)
+}
+
+/*---------------------------------------------------------------------------*/
+
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/**
+ *  Sleeps, which must take at least the given duration.
+ */
+#[test_case]
+fn test_sleep() {
+    let start = time::uptime();
+    sleep(Duration::from_millis(20));
+    assert!(time::uptime() - start >= Duration::from_millis(20));
+}
+
+/**
+ *  Schedules a one-shot timer, which must fire exactly once,
+ *  and another one, cancelled before firing.
+ */
+#[test_case]
+fn test_one_shot() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn callback(_id: TimerId) {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let id = after(Duration::from_millis(5), callback).unwrap();
+    let cancelled = after(Duration::from_millis(5), callback).unwrap();
+    assert!(cancel(cancelled));
+    sleep(Duration::from_millis(30));
+    assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+    assert!(!cancel(id));
+}
+
+/**
+ *  Schedules a periodic timer, which must keep firing until cancelled.
+ */
+#[test_case]
+fn test_periodic() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn callback(_id: TimerId) {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let id = every(Duration::from_millis(5), callback).unwrap();
+    sleep(Duration::from_millis(52));
+    assert!(cancel(id));
+    let fired = FIRED.load(Ordering::Relaxed);
+    assert!((5..=11).contains(&fired), "fired {} times", fired);
+    sleep(Duration::from_millis(20));
+    assert_eq!(FIRED.load(Ordering::Relaxed), fired);
+}
+
+/**
+ *  Schedules timers too far away to ever fire, which must stay
+ *  pending instead of overflowing.
+ */
+#[test_case]
+fn test_far_deadline() {
+    fn callback(_id: TimerId) {
+        panic!("far timer fired");
+    }
+
+    let once = after(Duration::MAX, callback).unwrap();
+    let periodic = every(Duration::MAX, callback).unwrap();
+    sleep(Duration::from_millis(5));
+    assert!(cancel(once));
+    assert!(cancel(periodic));
+}
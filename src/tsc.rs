@@ -0,0 +1,76 @@
+/*!
+ *  Time Stamp Counter (TSC).
+ *
+ *  Every CPU cycle (or, on newer CPUs, every tick of a fixed-rate
+ *  clock) increments the TSC, read in a few cycles by `rdtsc`. Its
+ *  frequency isn't reported reliably, so it's calibrated at boot.
+ *
+ *  Only an invariant TSC (as reported by CPUID) is guaranteed to run
+ *  at a constant rate across power states; otherwise, timestamps may
+ *  drift when CPU frequency changes.
+ */
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::time;
+
+/**
+ *  If CPU has a TSC, as reported by CPUID.
+ */
+pub fn is_supported() -> bool {
+    let edx = unsafe { __cpuid(1).edx };
+    edx & (1 << 4) != 0
+}
+
+/**
+ *  If TSC runs at a constant rate in every power state.
+ */
+pub fn is_invariant() -> bool {
+    let max_leaf = unsafe { __cpuid(0x8000_0000).eax };
+    max_leaf >= 0x8000_0007
+        && unsafe { __cpuid(0x8000_0007).edx } & (1 << 8) != 0
+}
+
+/**
+ *  Reads current TSC value.
+ */
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/**
+ *  Measures TSC frequency, counting its increments whilst
+ *  waiting on HPET (or PIT), and keeps it for `frequency`.
+ *
+ *  Returns the frequency, in Hz (`None` if there's no TSC).
+ */
+pub fn calibrate() -> Option<u64> {
+    if !is_supported() {
+        return None;
+    }
+    let start = read();
+    time::reference_wait(CALIBRATION_TIME);
+    let elapsed = read().wrapping_sub(start);
+    let frequency =
+        elapsed * 1_000_000 / CALIBRATION_TIME.as_micros() as u64;
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    Some(frequency).filter(|&frequency| frequency > 0)
+}
+
+/**
+ *  Gets the frequency measured by `calibrate`, in Hz
+ *  (zero if not calibrated).
+ */
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/*---------------------------------------------------------------------------*/
+
+/// Time spent counting TSC increments on calibration.
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+/// TSC increments per second, set on `calibrate`.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
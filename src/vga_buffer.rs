@@ -49,10 +49,27 @@ lazy_static! {  // delegates initialization to runtime and thus avoid errors
                 Color::Yellow, Color::Black, false
             ),
             buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            pointer: None,
+            under_pointer: ColorCode(0),
         }
     );
 }
 
+/**
+ *  Shows a (mouse) pointer at `(row, column)`, highlighting the
+ *  char there, or hides it if `None`.
+ *
+ *  Positions outside the screen are clamped to its edges.
+ */
+pub fn set_pointer(position: Option<(usize, usize)>) {
+    let position = position.map(|(row, col)| {
+        (row.min(BUFFER_HEIGHT - 1), col.min(BUFFER_WIDTH - 1))
+    });
+    without_interrupts(|| {
+        WRITER.lock().move_pointer(position);
+    });
+}
+
 /*---------------------------------------------------------------------------*/
 
 /**
@@ -127,10 +144,14 @@ struct ScreenChar {
 }
 
 /// Buffer default screen height.
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
 
 /// Buffer default screen width.
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// Color code of char under pointer.
+const POINTER_COLOR: ColorCode =
+    ColorCode::new(Color::Black, Color::LightGray, false);
 
 /** 
  *  Matrix buffer. Points to VGA text mode's memory I/O address.
@@ -157,6 +178,12 @@ pub struct Writer {
 
     /// Pointer to memory text buffer address.
     buffer: &'static mut Buffer,
+
+    /// Position of (mouse) pointer, if shown.
+    pointer: Option<(usize, usize)>,
+
+    /// Color code of char under pointer, restored when it leaves.
+    under_pointer: ColorCode,
 }
 
 impl Writer {
@@ -186,6 +213,10 @@ impl Writer {
                 // Write (two-byte) char to memory I/O buffer
                 // (`write` must be used as it is of the Volatile type)
                 self.buffer.chars[row][col].write(screen_char);
+                if self.pointer == Some((row, col)) {
+                    self.under_pointer = color_code;
+                    self.set_color(row, col, POINTER_COLOR);
+                }
 
                 // Increment column index
                 self.column_position += 1;
@@ -220,6 +251,10 @@ impl Writer {
             ascii_character: 0,
             color_code: ColorCode(0),
         };
+        // Pointer stays in place whilst text scrolls beneath it
+        let pointer = self.pointer;
+        self.move_pointer(None);
+
         // Start from topmost _written_ row (to avoid copying blank content)
         for row in self.top_row_position..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
@@ -238,6 +273,31 @@ impl Writer {
             self.top_row_position -= 1;
         }
         self.column_position = 0;
+        self.move_pointer(pointer);
+    }
+
+    /**
+     *  Moves (mouse) pointer, restoring text color of the char
+     *  it leaves and highlighting the one it reaches.
+     */
+    fn move_pointer(&mut self, position: Option<(usize, usize)>) {
+        if let Some((row, col)) = self.pointer {
+            self.set_color(row, col, self.under_pointer);
+        }
+        if let Some((row, col)) = position {
+            self.under_pointer = self.buffer.chars[row][col].read().color_code;
+            self.set_color(row, col, POINTER_COLOR);
+        }
+        self.pointer = position;
+    }
+
+    /**
+     *  Changes color of char at `(row, col)`, keeping the char.
+     */
+    fn set_color(&mut self, row: usize, col: usize, color_code: ColorCode) {
+        let mut screen_char = self.buffer.chars[row][col].read();
+        screen_char.color_code = color_code;
+        self.buffer.chars[row][col].write(screen_char);
     }
 }
 
@@ -347,3 +407,27 @@ fn test_print_all() {
         }
     });
 }
+
+/**
+ *  Passes pointer over a char of another color than the writer's,
+ *  checking that its own color is restored afterwards.
+ */
+#[test_case]
+fn test_pointer_color() {
+    let color_code = ColorCode::new(Color::Red, Color::Black, false);
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let pointer = writer.pointer;
+        writer.move_pointer(None);
+
+        writer.set_color(0, 0, color_code);
+        writer.move_pointer(Some((0, 0)));
+        let screen_char = writer.buffer.chars[0][0].read();
+        assert_eq!(screen_char.color_code.0, POINTER_COLOR.0);
+        writer.move_pointer(Some((0, 1)));
+        let screen_char = writer.buffer.chars[0][0].read();
+        assert_eq!(screen_char.color_code.0, color_code.0);
+
+        writer.move_pointer(pointer);
+    });
+}
@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(moon_os::test::runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*!
+ *  Legacy PIC fallback testing, forced on boot.
+ */
+
+mod panic;
+
+use bootloader::{entry_point, BootInfo};
+
+use moon_os::interrupts::{self, Controller};
+use moon_os::{memory, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe { memory::init(boot_info) };
+    interrupts::force_pic();
+    moon_os::init(true);
+    test_main();
+    moon_os::hlt_loop();
+}
+
+/**
+ *  Checks that PICs are used, even if an APIC is present.
+ */
+#[test_case]
+fn uses_pics() {
+    assert_eq!(interrupts::controller(), Controller::Pic);
+}
+
+/**
+ *  Checks that timer ticks keep coming through the PICs.
+ */
+#[test_case]
+fn ticks_through_pics() {
+    let start = time::ticks();
+    for _ in 0..100 {
+        if time::ticks() > start {
+            break;
+        }
+        x86_64::instructions::hlt();
+    }
+    assert!(time::ticks() > start, "no tick through the PICs");
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(moon_os::test::runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*!
+ *  Physical frame allocator testing.
+ */
+
+mod panic;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PhysFrame
+};
+
+use moon_os::memory::BootInfoFrameAllocator;
+
+/// Allocator under test, built from the memory map on boot.
+static ALLOCATOR: spin::Mutex<Option<BootInfoFrameAllocator>> =
+    spin::Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let allocator = unsafe {
+        BootInfoFrameAllocator::init(
+            &boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    *ALLOCATOR.lock() = Some(allocator);
+    test_main();
+    moon_os::hlt_loop();
+}
+
+/**
+ *  Allocates a few frames and checks they are all distinct
+ *  and that counters are updated accordingly.
+ */
+#[test_case]
+fn distinct_frames() {
+    let mut guard = ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let used = allocator.used_frames();
+
+    let mut frames = [None::<PhysFrame>; 16];
+    for slot in frames.iter_mut() {
+        *slot = allocator.allocate_frame();
+        assert!(slot.is_some());
+    }
+    for (i, a) in frames.iter().enumerate() {
+        for b in frames[i + 1..].iter() {
+            assert_ne!(a, b);
+        }
+    }
+    assert_eq!(allocator.used_frames(), used + 16);
+    assert_eq!(allocator.free_frames(),
+        allocator.total_frames() - allocator.used_frames());
+
+    for frame in frames.iter() {
+        unsafe { allocator.deallocate_frame(frame.unwrap()) };
+    }
+    assert_eq!(allocator.used_frames(), used);
+}
+
+/**
+ *  Checks that a deallocated frame is handed out again.
+ */
+#[test_case]
+fn reuse_after_deallocation() {
+    let mut guard = ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+    unsafe { allocator.deallocate_frame(frame) };
+}
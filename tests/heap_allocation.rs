@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(moon_os::test::runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*!
+ *  Heap allocation testing.
+ */
+
+extern crate alloc;
+
+mod panic;
+
+use alloc::{boxed::Box, vec::Vec, string::String, collections::BTreeMap};
+
+use bootloader::{entry_point, BootInfo};
+
+use moon_os::allocator::{self, HEAP_SIZE};
+use moon_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe { memory::init(boot_info) };
+    moon_os::init(true);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    moon_os::hlt_loop();
+}
+
+/**
+ *  Allocates a couple of boxes and checks their values.
+ */
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+/**
+ *  Grows a large vector, thus causing many reallocations.
+ */
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+/**
+ *  Allocates a single block taking about half of the heap.
+ */
+#[test_case]
+fn large_allocation() {
+    let mut vec = Vec::<u8>::with_capacity(HEAP_SIZE / 2);
+    vec.resize(HEAP_SIZE / 2, 0xaa);
+    assert!(vec.iter().all(|&byte| byte == 0xaa));
+}
+
+/**
+ *  Allocates many short-lived boxes, adding up to more than
+ *  the heap size, so freed memory must be reused.
+ */
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+/**
+ *  Same as `many_boxes`, but keeping a long-lived allocation
+ *  alive for the whole time.
+ *
+ *  Skipped for the bump allocator, which can't reuse memory
+ *  while any allocation is still alive.
+ */
+#[cfg(not(feature = "bump_allocator"))]
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+/**
+ *  Uses a few other `alloc` collections.
+ */
+#[test_case]
+fn collections() {
+    let mut map = BTreeMap::new();
+    for i in 0..100 {
+        let mut s = String::from("key");
+        s.push(char::from(b'0' + (i % 10) as u8));
+        map.insert(i, s);
+    }
+    assert_eq!(map.len(), 100);
+    assert_eq!(map[&42], "key2");
+}
@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(moon_os::test::runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*!
+ *  Heap growth and statistics testing.
+ */
+
+extern crate alloc;
+
+mod panic;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::alloc::Layout;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr
+};
+
+use moon_os::allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START};
+use moon_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe { memory::init(boot_info) };
+    moon_os::init(true);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    moon_os::hlt_loop();
+}
+
+/**
+ *  Checks that counters follow a single allocation.
+ */
+#[test_case]
+fn stats_follow_allocations() {
+    let before = allocator::stats();
+    let x = Box::new([0u64; 16]);
+    let during = allocator::stats();
+    assert_eq!(during.in_use, before.in_use + 128);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak >= during.in_use);
+    drop(x);
+    assert_eq!(allocator::stats().in_use, before.in_use);
+}
+
+/**
+ *  Allocates several times the initial heap size,
+ *  which must be mapped on demand.
+ */
+#[test_case]
+fn grows_beyond_initial_size() {
+    let buffers: Vec<Vec<u8>> =
+        (0..8).map(|i| vec![i as u8; HEAP_SIZE / 2]).collect();
+    for (i, buffer) in buffers.iter().enumerate() {
+        assert!(buffer.iter().all(|&byte| byte == i as u8));
+    }
+    let stats = allocator::stats();
+    assert!(stats.mapped >= 4 * HEAP_SIZE);
+    assert!(stats.mapped <= stats.max_size);
+    assert!(stats.peak >= 4 * HEAP_SIZE);
+}
+
+/**
+ *  Checks that allocations which would exceed
+ *  maximum heap size fail instead of growing it.
+ */
+#[test_case]
+fn respects_max_size() {
+    let layout = Layout::from_size_align(HEAP_MAX_SIZE, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(ptr.is_null());
+    assert!(allocator::stats().mapped <= HEAP_MAX_SIZE);
+
+    // Lowering limit to what's mapped stops growth entirely
+    let mapped = allocator::stats().mapped;
+    allocator::set_max_size(mapped);
+    let layout = Layout::from_size_align(mapped, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(ptr.is_null());
+    assert_eq!(allocator::stats().mapped, mapped);
+    allocator::set_max_size(HEAP_MAX_SIZE);
+}
+
+/**
+ *  Makes growth fail halfway, on a page already mapped past heap
+ *  end, checking that nothing is left mapped or leaked and that
+ *  heap grows again once the page is gone.
+ */
+#[test_case]
+fn recovers_from_mapping_failure() {
+    let mapped = allocator::stats().mapped;
+    let heap_end = VirtAddr::new((HEAP_START + mapped) as u64);
+    let first_page = Page::containing_address(heap_end);
+    let blocker = first_page + 1;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { memory::map_page(blocker, flags).unwrap() };
+    let used_frames = memory::frame_stats().used;
+
+    let layout = Layout::from_size_align(mapped, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(ptr.is_null());
+    assert_eq!(allocator::stats().mapped, mapped);
+    assert_eq!(memory::translate_addr(heap_end), None);
+    assert_eq!(memory::frame_stats().used, used_frames);
+
+    unsafe {
+        let frame = memory::unmap_page(blocker).unwrap();
+        memory::deallocate_frame(frame);
+    }
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(!ptr.is_null());
+    assert!(allocator::stats().mapped > mapped);
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+}
@@ -0,0 +1,138 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(moon_os::test::runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*!
+ *  Heap allocator stress testing.
+ *
+ *  Should be run once for each allocator backend, e.g.:
+ *  `cargo test --test heap_stress --no-default-features
+ *  --features fixed_size_block_allocator`.
+ */
+
+extern crate alloc;
+
+mod panic;
+
+use alloc::{vec, vec::Vec};
+use core::alloc::Layout;
+
+use bootloader::{entry_point, BootInfo};
+
+use moon_os::allocator;
+use moon_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    unsafe { memory::init(boot_info) };
+    moon_os::init(true);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    moon_os::hlt_loop();
+}
+
+/**
+ *  Xorshift pseudo-random number generator (deterministic seed).
+ */
+struct Rng(u64);
+
+impl Rng {
+    /**
+     *  Gets next number in range `1..=max`.
+     */
+    fn next(&mut self, max: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % max as u64) as usize + 1
+    }
+}
+
+/**
+ *  Allocates a batch of randomly sized buffers, checks
+ *  their contents and frees all of them, in many rounds.
+ */
+#[test_case]
+fn alloc_free_rounds() {
+    let mut rng = Rng(0x_2545_f491_4f6c_dd1d);
+    for _ in 0..50 {
+        let mut buffers = Vec::with_capacity(64);
+        for i in 0..64 {
+            let size = rng.next(512);
+            buffers.push(vec![i as u8; size]);
+        }
+        for (i, buffer) in buffers.iter().enumerate() {
+            assert!(buffer.iter().all(|&byte| byte == i as u8));
+        }
+    }
+}
+
+/**
+ *  Allocates with every power-of-two alignment up to a page,
+ *  checking that returned addresses are correctly aligned.
+ */
+#[test_case]
+fn varied_alignments() {
+    for shift in 0..=12 {
+        let align = 1 << shift;
+        let layout = Layout::from_size_align(24, align).unwrap();
+        unsafe {
+            let ptr = alloc::alloc::alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            ptr.write_bytes(0xa5, layout.size());
+            alloc::alloc::dealloc(ptr, layout);
+        }
+    }
+}
+
+/**
+ *  Randomly replaces buffers from a fixed set of slots,
+ *  interleaving allocations and frees of different sizes.
+ *
+ *  Skipped for the bump allocator, which never reuses memory
+ *  while any allocation is still alive.
+ */
+#[cfg(not(feature = "bump_allocator"))]
+#[test_case]
+fn random_replacement() {
+    let mut rng = Rng(0x_9e37_79b9_7f4a_7c15);
+    let mut slots: Vec<Vec<u8>> = (0..32).map(|_| Vec::new()).collect();
+    for i in 0..10_000 {
+        let slot = rng.next(slots.len()) - 1;
+        let tag = slots[slot].first().copied();
+        assert!(slots[slot].iter().all(|&byte| Some(byte) == tag));
+        slots[slot] = vec![i as u8; rng.next(512)];
+    }
+}
+
+/**
+ *  Fills most of the heap with small blocks, frees them in
+ *  scattered order, then allocates a single large block, which
+ *  fits without growing the heap only if freed neighbours
+ *  have been coalesced.
+ */
+#[cfg(feature = "linked_list_allocator")]
+#[test_case]
+fn coalescing() {
+    use alloc::boxed::Box;
+
+    let count = allocator::HEAP_SIZE / 2 / 64;
+    let mut blocks: Vec<Option<Box<[u8; 64]>>> =
+        (0..count).map(|_| Some(Box::new([0; 64]))).collect();
+    for step in [2, 3, 1] {
+        for block in blocks.iter_mut().step_by(step) {
+            block.take();
+        }
+    }
+    drop(blocks);
+
+    // Heap mustn't have grown to fit large block
+    let mapped = allocator::stats().mapped;
+    let large = vec![0u8; allocator::HEAP_SIZE * 3 / 4];
+    assert_eq!(large.len(), allocator::HEAP_SIZE * 3 / 4);
+    assert_eq!(allocator::stats().mapped, mapped);
+}
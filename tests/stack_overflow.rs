@@ -12,7 +12,11 @@
 
 mod panic;
 
-use moon_os::serial_print;
+use bootloader::{entry_point, BootInfo};
+
+use moon_os::{memory, serial_print};
+
+entry_point!(main);
 
 /**
  *  Causes a stack oveflow by recursing endlessly.
@@ -28,9 +32,9 @@ fn stack_overflow() {
     Volatile::new(0).read();
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
+    unsafe { memory::init(boot_info) };
     moon_os::init(true);
     panic::set_success_on_panic();
     stack_overflow();
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+
+/*!
+ *  Symbolized backtrace test.
+ *
+ *  Panics inside a known function and checks that the backtrace
+ *  printed on serial output resolves a frame to its name.
+ */
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+
+use moon_os::backtrace::Backtrace;
+use moon_os::{memory, serial_print, serial_println};
+use moon_os::qemu;
+
+entry_point!(main);
+
+/// Expected in backtrace, as `function+offset`.
+const EXPECTED_FRAME: &str = "symbolized_backtrace::known_function+";
+
+/**
+ *  Panics right away, thus being in the backtrace.
+ */
+#[inline(never)]
+fn known_function() {
+    panic!("panicking inside known function");
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("symbolized_backtrace::known_function...\t");
+    // Registers the kernel stack, which backtraces are walked within
+    unsafe { memory::init(boot_info) };
+    moon_os::init(true);
+    known_function();
+    serial_println!("[test did not panic!]");
+    qemu::exit(qemu::ExitCode::Failed);
+}
+
+/**
+ *  Formats backtrace, printing it on serial and
+ *  checking whether it contains the expected frame.
+ */
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    let mut output = Buffer { bytes: [0; 4096], len: 0 };
+    let _ = write!(output, "{}", Backtrace::capture());
+    let output = core::str::from_utf8(&output.bytes[..output.len])
+        .unwrap_or("");
+    if output.contains(EXPECTED_FRAME) {
+        serial_println!("[ok]\n{}", output);
+        qemu::exit(qemu::ExitCode::Success);
+    }
+    serial_println!("[failed]\n{}", output);
+    serial_println!("Error: `{}` not found in backtrace", EXPECTED_FRAME);
+    qemu::exit(qemu::ExitCode::Failed);
+}
+
+/**
+ *  Fixed-size text buffer, silently truncating what doesn't fit.
+ */
+struct Buffer {
+    bytes: [u8; 4096],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n]
+            .copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+#!/bin/sh
+# Cargo runner: fills the symbol table of the kernel ELF (first
+# argument) and then boots it through `bootimage runner`.
+set -e
+tool_dir=$(dirname "$0")
+host=$(rustc -vV | sed -n 's/^host: //p')
+cargo run --quiet --release --target "$host" \
+    --manifest-path "$tool_dir/Cargo.toml" -- "$1"
+exec bootimage runner "$@"
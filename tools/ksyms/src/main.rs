@@ -0,0 +1,122 @@
+/*!
+ *  Embeds function symbols into the kernel ELF image.
+ *
+ *  Extracts every function symbol from the ELF symbol table and
+ *  writes them, sorted by address, into the `.ksyms` section reserved
+ *  by `moon_os::symbols` (whose doc describes the table layout).
+ *  As the section keeps its size, no address is changed by this.
+ *
+ *  Usage: `ksyms <kernel ELF>` (file is patched in place).
+ */
+
+use std::{env, fs, process};
+
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+/// Expected at the start of `.ksyms`.
+const MAGIC: &[u8; 8] = b"MOONSYMS";
+
+/// Size of table header (magic and count) in bytes.
+const HEADER_SIZE: usize = 16;
+
+/// Size of each table entry in bytes.
+const ENTRY_SIZE: usize = 16;
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: ksyms <kernel ELF>");
+            process::exit(2);
+        }
+    };
+    if let Err(err) = embed_symbols(&path) {
+        eprintln!("ksyms: {}: {}", path, err);
+        process::exit(1);
+    }
+}
+
+/**
+ *  Function symbol to be written in the table.
+ */
+struct Symbol {
+    address: u64,
+    size: u32,
+    name: String,
+}
+
+/**
+ *  Reads ELF file at `path`, filling its symbol table.
+ */
+fn embed_symbols(path: &str) -> Result<(), String> {
+    let mut elf = fs::read(path).map_err(|err| err.to_string())?;
+
+    let (offset, size, symbols) = {
+        let file = object::File::parse(&*elf)
+            .map_err(|err| err.to_string())?;
+        let section = file.section_by_name(".ksyms")
+            .ok_or("no `.ksyms` section found")?;
+        let (offset, size) = section.file_range()
+            .ok_or("`.ksyms` section has no data in file")?;
+        (offset as usize, size as usize, function_symbols(&file))
+    };
+    let section = &mut elf[offset..offset + size];
+    if size < HEADER_SIZE || &section[..MAGIC.len()] != MAGIC {
+        return Err("`.ksyms` section doesn't start with magic".into());
+    }
+    let table = encode(&symbols);
+    let capacity = size - HEADER_SIZE;
+    if table.len() > capacity {
+        return Err(format!(
+            "table takes {} bytes, but only {} are reserved \
+            (raise `symbols::CAPACITY`)", table.len(), capacity));
+    }
+    section[8..12].copy_from_slice(&(symbols.len() as u32).to_le_bytes());
+    let data = &mut section[HEADER_SIZE..];
+    data[..table.len()].copy_from_slice(&table);
+    for byte in data[table.len()..].iter_mut() {
+        *byte = 0;
+    }
+
+    fs::write(path, &elf).map_err(|err| err.to_string())
+}
+
+/**
+ *  Collects defined function symbols, sorted by address
+ *  (keeping only the first name of aliased functions).
+ */
+fn function_symbols(file: &object::File) -> Vec<Symbol> {
+    let mut symbols: Vec<Symbol> = file.symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text)
+        .filter(|symbol| symbol.address() != 0 && symbol.size() != 0)
+        .filter_map(|symbol| {
+            let name = symbol.name().ok()?;
+            Some(Symbol {
+                address: symbol.address(),
+                size: symbol.size() as u32,
+                // Alternate format leaves out the hash suffix
+                name: format!("{:#}", rustc_demangle::demangle(name)),
+            })
+        })
+        .collect();
+    symbols.sort_by_key(|symbol| symbol.address);
+    symbols.dedup_by_key(|symbol| symbol.address);
+    symbols
+}
+
+/**
+ *  Encodes entries followed by NUL-terminated names.
+ */
+fn encode(symbols: &[Symbol]) -> Vec<u8> {
+    let mut entries = Vec::with_capacity(symbols.len() * ENTRY_SIZE);
+    let mut names = Vec::new();
+    for symbol in symbols {
+        entries.extend_from_slice(&symbol.address.to_le_bytes());
+        entries.extend_from_slice(&symbol.size.to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+        names.push(0);
+    }
+    entries.extend_from_slice(&names);
+    entries
+}